@@ -0,0 +1,372 @@
+use crate::{
+  dnserror::DnsError,
+  dnsmessage::{normalize_name, DnsMessage, DnsQuestion, DnsRecord, QueryType, ResultCode},
+};
+use std::{
+  collections::HashMap,
+  fs,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr},
+  path::PathBuf,
+  str::FromStr,
+};
+
+/// Names that show up in hosts-format blocklists pointing at themselves, which
+/// we'd rather not block
+const HOSTS_NAMES: [&str; 6] = [
+  "localhost",
+  "localhost.localdomain",
+  "local",
+  "broadcasthost",
+  "ip6-localhost",
+  "ip6-loopback",
+];
+
+/// What a client gets back for a blocked name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BlockAction {
+  NxDomain,
+  Refused,
+  // A and AAAA questions get these addresses, where we have one; anything else
+  // gets an empty answer. `null` is this with 0.0.0.0 and ::
+  Sinkhole(Option<Ipv4Addr>, Option<Ipv6Addr>),
+}
+
+impl FromStr for BlockAction {
+  type Err = DnsError;
+
+  fn from_str(s: &str) -> Result<BlockAction, DnsError> {
+    match s {
+      "nxdomain" => return Ok(BlockAction::NxDomain),
+      "refused" => return Ok(BlockAction::Refused),
+      "null" => {
+        return Ok(BlockAction::Sinkhole(
+          Some(Ipv4Addr::UNSPECIFIED),
+          Some(Ipv6Addr::UNSPECIFIED),
+        ))
+      }
+      _ => {}
+    }
+    // otherwise it's a sinkhole: an ipv4 address, an ipv6 one, or one of each
+    let bad = || {
+      DnsError::from(
+        format!(
+          "unknown block action {:?}, expected nxdomain, refused, null or sinkhole addresses",
+          s
+        )
+        .as_str(),
+      )
+    };
+    let (mut v4, mut v6) = (None, None);
+    for field in s.split_whitespace() {
+      match field.parse::<IpAddr>().map_err(|_| bad())? {
+        IpAddr::V4(a) if v4.is_none() => v4 = Some(a),
+        IpAddr::V6(a) if v6.is_none() => v6 = Some(a),
+        _ => return Err(bad()),
+      }
+    }
+    if v4.is_none() && v6.is_none() {
+      return Err(bad());
+    }
+    Ok(BlockAction::Sinkhole(v4, v6))
+  }
+}
+
+/// One label of the trie. Names go in backwards, a label at a time, so
+/// ads.example.com is com -> example -> ads, and everything under a blocked node
+/// is blocked too.
+#[derive(Debug, Default)]
+struct Node {
+  blocked: bool,
+  children: HashMap<String, Node>,
+}
+
+/// Names we won't resolve for anyone, along with every name under them
+#[derive(Debug)]
+pub(crate) struct Blocklist {
+  root: Node,
+  action: BlockAction,
+  ttl: u32,
+}
+
+impl Blocklist {
+  /// Read every list in `paths`. Lists we can't read are logged and skipped, as
+  /// are lines that aren't a domain in one of the formats we know.
+  pub fn load(paths: &[PathBuf], action: BlockAction, ttl: u32) -> Blocklist {
+    let mut blocklist = Blocklist {
+      root: Node::default(),
+      action,
+      ttl,
+    };
+    for path in paths {
+      match fs::read_to_string(path) {
+        Ok(text) => {
+          let (added, skipped) = blocklist.parse(&text);
+          eprintln!(
+            "loaded {} names from blocklist {:?}, skipped {} lines",
+            added, path, skipped
+          );
+        }
+        Err(e) => eprintln!("couldn't read blocklist {:?}: {}", path, e),
+      }
+    }
+    blocklist
+  }
+
+  /// Lists can be in any of these formats, even mixed together:
+  ///
+  /// - hosts files, `0.0.0.0 ads.example.com`, where the address doesn't matter
+  /// - one domain per line, `ads.example.com`
+  /// - adblock rules, `||ads.example.com^`
+  ///
+  /// `#` starts a comment, and so does `!` for adblock lists. Adblock rules with
+  /// options, wildcards or exceptions are for browsers, so we skip them.
+  ///
+  /// Returns how many names were added, and how many lines were skipped.
+  fn parse(&mut self, text: &str) -> (usize, usize) {
+    let (mut added, mut skipped) = (0, 0);
+    for line in text.lines() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+        continue;
+      }
+      let names: Vec<&str> = if let Some(rule) = line.strip_prefix("||") {
+        match rule.strip_suffix('^') {
+          Some(name) => vec![name],
+          None => {
+            skipped += 1;
+            continue;
+          }
+        }
+      } else {
+        let line = line.split('#').next().unwrap_or_default();
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.split_first() {
+          None => continue,
+          Some((first, rest)) if first.parse::<IpAddr>().is_ok() => rest
+            .iter()
+            .copied()
+            .filter(|n| !HOSTS_NAMES.contains(n))
+            .collect(),
+          Some((first, [])) => vec![*first],
+          Some(_) => {
+            skipped += 1;
+            continue;
+          }
+        }
+      };
+      for name in names {
+        let name = normalize_name(name);
+        if !is_domain(&name) {
+          skipped += 1;
+          continue;
+        }
+        if self.insert(&name) {
+          added += 1;
+        }
+      }
+    }
+    (added, skipped)
+  }
+
+  /// Block `name` and everything under it. Returns false if it already was.
+  fn insert(&mut self, name: &str) -> bool {
+    let mut node = &mut self.root;
+    for label in name.rsplit('.') {
+      if node.blocked {
+        return false;
+      }
+      node = node.children.entry(label.to_string()).or_default();
+    }
+    if node.blocked {
+      return false;
+    }
+    node.blocked = true;
+    // anything under here is covered now
+    node.children = HashMap::new();
+    true
+  }
+
+  /// Whether `name`, or any name it's under, is blocked
+  pub fn is_blocked(&self, name: &str) -> bool {
+    let mut node = &self.root;
+    for label in name.rsplit('.') {
+      match node.children.get(label) {
+        Some(child) if child.blocked => return true,
+        Some(child) => node = child,
+        None => return false,
+      }
+    }
+    false
+  }
+
+  /// Our answer to `question` if its name is blocked, as `block_action` says
+  pub fn lookup(&self, question: &DnsQuestion) -> Option<DnsMessage> {
+    if question.class != 1 || question.name.is_empty() || !self.is_blocked(&question.name) {
+      return None;
+    }
+    let mut answer = DnsMessage::default();
+    match self.action {
+      BlockAction::NxDomain => answer.flags.rcode = ResultCode::NXDOMAIN,
+      BlockAction::Refused => answer.flags.rcode = ResultCode::REFUSED,
+      BlockAction::Sinkhole(v4, v6) => {
+        let any = question.qtype == QueryType::ANY;
+        let domain = question.name.clone();
+        let ttl = self.ttl;
+        if let Some(addr) = v4.filter(|_| any || question.qtype == QueryType::A) {
+          answer.answers.push(DnsRecord::A {
+            domain: domain.clone(),
+            addr,
+            ttl,
+          });
+        }
+        if let Some(addr) = v6.filter(|_| any || question.qtype == QueryType::AAAA) {
+          answer.answers.push(DnsRecord::AAAA { domain, addr, ttl });
+        }
+      }
+    }
+    Some(answer)
+  }
+}
+
+/// Whether `name` looks like something we can block: labels of letters, digits,
+/// `-` and `_`, with no wildcards or paths
+fn is_domain(name: &str) -> bool {
+  !name.is_empty()
+    && name.split('.').all(|label| {
+      !label.is_empty()
+        && label.len() <= 63
+        && label
+          .bytes()
+          .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn blocklist(text: &str, action: BlockAction) -> (Blocklist, (usize, usize)) {
+    let mut blocklist = Blocklist::load(&[], action, 60);
+    let counts = blocklist.parse(text);
+    (blocklist, counts)
+  }
+
+  #[test]
+  fn every_format_is_understood() {
+    let text = "# hosts\n0.0.0.0 ads.example.test tracker.example.test # trailing\n127.0.0.1 localhost\n! adblock\n[Adblock Plus 2.0]\n||doubleclick.test^\nPlain.Example.Test.\n";
+    let (list, (added, skipped)) = blocklist(text, BlockAction::NxDomain);
+    assert_eq!((added, skipped), (4, 0));
+    for name in [
+      "ads.example.test",
+      "tracker.example.test",
+      "doubleclick.test",
+      "plain.example.test",
+    ] {
+      assert!(list.is_blocked(name), "{}", name);
+    }
+    assert!(!list.is_blocked("localhost"));
+  }
+
+  #[test]
+  fn browser_rules_are_skipped() {
+    let text = "||options.test^$third-party\n@@||exception.test^\n||*.wild.test^\n/ads/banner.gif\ntwo words\n";
+    let (list, (added, skipped)) = blocklist(text, BlockAction::NxDomain);
+    assert_eq!((added, skipped), (0, 5));
+    assert!(!list.is_blocked("options.test"));
+    assert!(!list.is_blocked("exception.test"));
+  }
+
+  #[test]
+  fn names_under_a_blocked_name_are_blocked() {
+    let (list, (added, _)) = blocklist(
+      "example.test\nads.example.test\nexample.test\n",
+      BlockAction::NxDomain,
+    );
+    // the second and third add nothing the first didn't already cover
+    assert_eq!(added, 1);
+    assert!(list.is_blocked("example.test"));
+    assert!(list.is_blocked("a.b.example.test"));
+    assert!(!list.is_blocked("test"));
+    assert!(!list.is_blocked("notexample.test"));
+    assert!(!list.is_blocked("example.test.other"));
+  }
+
+  #[test]
+  fn blocked_names_get_the_configured_action() {
+    let text = "ads.test\n";
+    let (list, _) = blocklist(text, BlockAction::NxDomain);
+    assert_eq!(
+      list
+        .lookup(&DnsQuestion::new("ads.test", QueryType::A))
+        .unwrap()
+        .flags
+        .rcode,
+      ResultCode::NXDOMAIN
+    );
+    assert!(list
+      .lookup(&DnsQuestion::new("fine.test", QueryType::A))
+      .is_none());
+
+    let (list, _) = blocklist(text, BlockAction::Refused);
+    assert_eq!(
+      list
+        .lookup(&DnsQuestion::new("ads.test", QueryType::A))
+        .unwrap()
+        .flags
+        .rcode,
+      ResultCode::REFUSED
+    );
+
+    let (list, _) = blocklist(text, "null".parse().unwrap());
+    let a = list
+      .lookup(&DnsQuestion::new("x.ads.test", QueryType::A))
+      .unwrap();
+    assert_eq!(
+      a.answers,
+      vec![DnsRecord::A {
+        domain: "x.ads.test".into(),
+        addr: Ipv4Addr::UNSPECIFIED,
+        ttl: 60,
+      }]
+    );
+    assert_eq!(
+      list
+        .lookup(&DnsQuestion::new("ads.test", QueryType::ANY))
+        .unwrap()
+        .answers
+        .len(),
+      2
+    );
+    assert!(list
+      .lookup(&DnsQuestion::new("ads.test", QueryType::MX))
+      .unwrap()
+      .answers
+      .is_empty());
+
+    // only a v4 sinkhole, so AAAA gets an empty answer
+    let (list, _) = blocklist(text, "10.9.9.9".parse().unwrap());
+    let aaaa = list
+      .lookup(&DnsQuestion::new("ads.test", QueryType::AAAA))
+      .unwrap();
+    assert_eq!(aaaa.flags.rcode, ResultCode::NOERROR);
+    assert!(aaaa.answers.is_empty());
+  }
+
+  #[test]
+  fn block_actions_parse() {
+    assert_eq!(
+      "nxdomain".parse::<BlockAction>().unwrap(),
+      BlockAction::NxDomain
+    );
+    assert_eq!(
+      "10.0.0.1 fd00::1".parse::<BlockAction>().unwrap(),
+      BlockAction::Sinkhole(
+        Some(Ipv4Addr::new(10, 0, 0, 1)),
+        Some("fd00::1".parse().unwrap())
+      )
+    );
+    for bad in ["", "bogus", "10.0.0.1 10.0.0.2", "10.0.0.1 nope"] {
+      assert!(bad.parse::<BlockAction>().is_err(), "{:?}", bad);
+    }
+  }
+}
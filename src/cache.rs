@@ -0,0 +1,947 @@
+use crate::{
+  config::Config,
+  dnserror::DnsError,
+  dnsmessage::{DnsMessage, DnsQuestion, DnsRecord, ResultCode},
+};
+use std::{
+  collections::{BTreeMap, HashMap},
+  fs,
+  path::Path,
+  sync::{Arc, Condvar, Mutex},
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/*
+    A cache snapshot on disk is a header, then one record per cached answer:
+
+        "DINOCACHE"   magic
+        u16           format version
+        then for each answer:
+        u64           when it was stored, in seconds since the epoch
+        u64           when it expires, in seconds since the epoch
+        u8            length of the view name that follows
+        [u8]          the name of the view it was fetched for, empty outside views
+        u16           length of the message that follows
+        [u8]          the answer as a dns message, question and all
+
+    Everything is big endian, like the wire format.
+*/
+const SNAPSHOT_MAGIC: &[u8] = b"DINOCACHE";
+const SNAPSHOT_VERSION: u16 = 1;
+
+/// The view an answer was fetched for (empty outside of views) and the question
+/// it answers. Views can forward the same name to different places, so they can't
+/// share answers.
+type Key = (String, DnsQuestion);
+
+/// An answer we've been given, and when it stops being good
+#[derive(Debug, Clone)]
+struct Entry {
+  // only the rcode, AD bit and record sections matter; ttls are as we stored them
+  answer: DnsMessage,
+  stored: Instant,
+  expires: Instant,
+  // how long it was good for when we stored it, in seconds
+  ttl: u32,
+  // how many times we've answered from it since it was stored
+  hits: u32,
+  // set while we're at upstream for a fresh copy, so only one lookup goes at once
+  refreshing: bool,
+  // position in the lru order; bigger is more recent
+  last_used: u64,
+}
+
+/// A fresh answer from the cache
+#[derive(Debug)]
+pub(crate) struct Hit {
+  pub answer: DnsMessage,
+  // the entry is popular and nearly expired; it's worth fetching again now
+  pub prefetch: bool,
+}
+
+/// A lookup under way for a question we had nothing cached for, which anyone else
+/// missing on the same question can wait on instead of asking upstream themselves
+#[derive(Debug, Default)]
+pub(crate) struct Lookup {
+  // None until it's done, then the answer, or None again if upstream let us down
+  answer: Mutex<Option<Option<DnsMessage>>>,
+  done: Condvar,
+}
+
+impl Lookup {
+  /// Wait up to `timeout` for the lookup to finish, and give back what it got.
+  /// None if it failed, or is taking longer than that.
+  pub fn wait(&self, timeout: Duration) -> Option<DnsMessage> {
+    let answer = self.answer.lock().unwrap();
+    let (answer, _) = self
+      .done
+      .wait_timeout_while(answer, timeout, |a| a.is_none())
+      .unwrap();
+    answer.clone().flatten()
+  }
+}
+
+/// Who goes upstream for a question we have nothing cached for
+pub(crate) enum Claim<'a> {
+  // it's ours to make
+  Owner(LookupOwner<'a>),
+  // someone else is already at it
+  Waiter(Arc<Lookup>),
+}
+
+/// Our claim on the upstream lookup for a question. Whatever becomes of the
+/// lookup, everyone waiting on it hears back: an owner dropped without `end`
+/// being called, say because the lookup panicked, ends it with no answer.
+pub(crate) struct LookupOwner<'a> {
+  cache: &'a Cache,
+  view: String,
+  question: DnsQuestion,
+  ended: bool,
+}
+
+impl LookupOwner<'_> {
+  /// Hand `answer` to everyone waiting on the lookup, None if there isn't one,
+  /// and let the next miss go upstream again
+  pub fn end(mut self, answer: Option<&DnsMessage>) {
+    self.ended = true;
+    self.cache.end_lookup(&self.view, &self.question, answer);
+  }
+}
+
+impl Drop for LookupOwner<'_> {
+  fn drop(&mut self) {
+    if !self.ended {
+      self.cache.end_lookup(&self.view, &self.question, None);
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+  map: HashMap<Key, Entry>,
+  // last_used -> key, so the least recently used entry is always first
+  lru: BTreeMap<u64, Key>,
+  tick: u64,
+}
+
+/// Answers from upstream, kept until their ttl runs out so repeat questions don't
+/// have to go back out over the network. That includes answers saying there's
+/// nothing there, which are good for as long as their zone's SOA says.
+///
+/// Entries are keyed by the view and question (name, type and class). When the cache is
+/// full, the entry that has gone unused the longest makes way for the new one.
+///
+/// Expired entries hang around for a while longer, in case upstream can't be
+/// reached and stale data is better than none (RFC 8767).
+#[derive(Debug)]
+pub(crate) struct Cache {
+  entries: Mutex<Entries>,
+  max_entries: usize,
+  min_ttl: u32,
+  max_ttl: u32,
+  negative_max_ttl: u32,
+  // seconds past expiry we'll still serve an entry if upstream is unreachable
+  stale_window: u64,
+  // the ttl stale answers go out with
+  stale_ttl: u32,
+  // hits an entry needs before we'll refresh it ahead of expiry; 0 never does
+  prefetch_hits: u32,
+  // held while a snapshot is written, so the periodic save and the one at
+  // shutdown can't trip over each other's temp file
+  save_lock: Mutex<()>,
+  // lookups under way for questions that weren't in the cache at all
+  lookups: Mutex<HashMap<Key, Arc<Lookup>>>,
+}
+
+impl Cache {
+  pub fn new(c: &Config) -> Cache {
+    Cache {
+      entries: Mutex::new(Entries::default()),
+      max_entries: c.cache_size,
+      min_ttl: c.cache_min_ttl,
+      max_ttl: c.cache_max_ttl.max(c.cache_min_ttl),
+      negative_max_ttl: c.cache_negative_max_ttl.max(c.cache_min_ttl),
+      stale_window: c.cache_stale_window,
+      stale_ttl: c.cache_stale_ttl,
+      prefetch_hits: c.cache_prefetch_hits,
+      save_lock: Mutex::new(()),
+      lookups: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// A copy of the cached answer to `question`, with its ttls counted down by
+  /// however long it's been sitting here.
+  ///
+  /// Popular entries in the last tenth of their life get flagged for prefetching,
+  /// unless a refresh is already under way, so they can be refreshed before anyone
+  /// has to wait on them.
+  pub fn get(&self, view: &str, question: &DnsQuestion) -> Option<Hit> {
+    let key = (view.to_string(), question.clone());
+    let mut entries = self.entries.lock().unwrap();
+    let now = Instant::now();
+    let last_used = match entries.map.get(&key) {
+      Some(e) if e.expires > now => e.last_used,
+      Some(e) => {
+        if now >= e.expires + Duration::from_secs(self.stale_window) {
+          entries.remove(&key);
+        }
+        return None;
+      }
+      None => return None,
+    };
+    let tick = entries.touch(&key, last_used);
+    let entry = entries.map.get_mut(&key)?;
+    entry.last_used = tick;
+    entry.hits += 1;
+
+    let remaining = entry.expires.duration_since(now).as_secs();
+    let prefetch = self.prefetch_hits > 0
+      && !entry.refreshing
+      && entry.hits >= self.prefetch_hits
+      && remaining * 10 <= u64::from(entry.ttl);
+    if prefetch {
+      entry.refreshing = true;
+    }
+
+    let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+    let mut answer = entry.answer.clone();
+    set_ttls(&mut answer, |ttl| ttl.saturating_sub(elapsed));
+    Some(Hit { answer, prefetch })
+  }
+
+  /// An expired answer to `question` that's still inside the stale window, for
+  /// when upstream lets us down. Every record goes out with the stale ttl, so
+  /// clients come back soon for the real thing.
+  pub fn get_stale(&self, view: &str, question: &DnsQuestion) -> Option<DnsMessage> {
+    let entries = self.entries.lock().unwrap();
+    let entry = entries.map.get(&(view.to_string(), question.clone()))?;
+    if entry.expires + Duration::from_secs(self.stale_window) <= Instant::now() {
+      return None;
+    }
+    let mut answer = entry.answer.clone();
+    set_ttls(&mut answer, |_| self.stale_ttl);
+    Some(answer)
+  }
+
+  /// Claim the refresh of the entry for `question`, so only one lookup for it goes
+  /// to upstream at a time. False if someone else already has it, or it's gone.
+  pub fn start_refresh(&self, view: &str, question: &DnsQuestion) -> bool {
+    let mut entries = self.entries.lock().unwrap();
+    match entries.map.get_mut(&(view.to_string(), question.clone())) {
+      Some(entry) if !entry.refreshing => {
+        entry.refreshing = true;
+        true
+      }
+      _ => false,
+    }
+  }
+
+  /// Let the next lookup refresh `question` again, once we're done. An answer
+  /// that made it into the cache already replaced the entry, so this only matters
+  /// when upstream let us down.
+  pub fn end_refresh(&self, view: &str, question: &DnsQuestion) {
+    let mut entries = self.entries.lock().unwrap();
+    if let Some(entry) = entries.map.get_mut(&(view.to_string(), question.clone())) {
+      entry.refreshing = false;
+    }
+  }
+
+  /// Claim the upstream lookup for a question we have nothing cached for, so a
+  /// crowd missing on it at once only asks upstream the once
+  pub fn start_lookup(&self, view: &str, question: &DnsQuestion) -> Claim<'_> {
+    let mut lookups = self.lookups.lock().unwrap();
+    let key = (view.to_string(), question.clone());
+    match lookups.get(&key) {
+      Some(lookup) => Claim::Waiter(lookup.clone()),
+      None => {
+        lookups.insert(key, Arc::new(Lookup::default()));
+        Claim::Owner(LookupOwner {
+          cache: self,
+          view: view.to_string(),
+          question: question.clone(),
+          ended: false,
+        })
+      }
+    }
+  }
+
+  fn end_lookup(&self, view: &str, question: &DnsQuestion, answer: Option<&DnsMessage>) {
+    let lookup = self
+      .lookups
+      .lock()
+      .unwrap()
+      .remove(&(view.to_string(), question.clone()));
+    if let Some(lookup) = lookup {
+      *lookup.answer.lock().unwrap() = Some(answer.cloned());
+      lookup.done.notify_all();
+    }
+  }
+
+  /// Hang on to an upstream's answer, if it's one worth keeping: successful answers
+  /// with records in them, and NXDOMAIN and NODATA answers that come with an SOA to
+  /// say how long they hold for.
+  ///
+  /// The answer's ttls are clamped in place, so the client that asked first sees
+  /// the same ttls as everyone served from the cache afterwards.
+  pub fn insert(&self, view: &str, question: &DnsQuestion, answer: &mut DnsMessage) {
+    if self.max_entries == 0 || answer.flags.truncated {
+      return;
+    }
+    let ttl = match self.ttl_of(answer) {
+      Some(ttl) if ttl > 0 => ttl,
+      _ => return,
+    };
+    let answer = DnsMessage {
+      flags: answer.flags.clone(),
+      answers: answer.answers.clone(),
+      authorities: answer.authorities.clone(),
+      additionals: answer.additionals.clone(),
+      ..DnsMessage::default()
+    };
+    self.put(
+      (view.to_string(), question.clone()),
+      answer,
+      Instant::now(),
+      ttl,
+    );
+  }
+
+  /// Store an answer good for `ttl` seconds from `stored`, making room for it if
+  /// we have to.
+  fn put(&self, key: Key, answer: DnsMessage, stored: Instant, ttl: u32) {
+    let mut entries = self.entries.lock().unwrap();
+    if let Some(old) = entries.map.get(&key) {
+      let last_used = old.last_used;
+      entries.lru.remove(&last_used);
+    }
+    while entries.map.len() >= self.max_entries && !entries.map.contains_key(&key) {
+      match entries.lru.keys().next().copied() {
+        Some(oldest) => {
+          let oldest = entries.lru[&oldest].clone();
+          entries.remove(&oldest);
+        }
+        None => break,
+      }
+    }
+    entries.tick += 1;
+    let tick = entries.tick;
+    entries.lru.insert(tick, key.clone());
+    entries.map.insert(
+      key,
+      Entry {
+        answer,
+        stored,
+        expires: stored + Duration::from_secs(ttl as u64),
+        ttl,
+        hits: 0,
+        refreshing: false,
+        last_used: tick,
+      },
+    );
+  }
+
+  /// Write everything in the cache to `path`, least recently used first so loading
+  /// it back rebuilds the same lru order.
+  ///
+  /// The file is written alongside and renamed into place, so a crash halfway
+  /// through never leaves a half-written snapshot behind. Only one save runs at a
+  /// time, and each takes its copy of the cache once it's its turn, so whichever
+  /// writes last also has the latest answers. Queries only wait on the copying,
+  /// not the encoding.
+  pub fn save(&self, path: &Path) -> Result<usize, DnsError> {
+    let _saving = self.save_lock.lock().unwrap();
+    let now = Instant::now();
+    let now_unix = unix_time();
+    let snapshot: Vec<(Key, Entry)> = {
+      let entries = self.entries.lock().unwrap();
+      entries
+        .lru
+        .values()
+        .map(|key| (key.clone(), entries.map[key].clone()))
+        .collect()
+    };
+
+    let mut out = Vec::new();
+    out.extend_from_slice(SNAPSHOT_MAGIC);
+    out.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+    let mut count = 0;
+    for ((view, question), entry) in snapshot {
+      let stored = now_unix.saturating_sub(now.saturating_duration_since(entry.stored).as_secs());
+      let message = DnsMessage {
+        questions: vec![question],
+        ..entry.answer
+      };
+      let bytes = match message.to_wire(u16::MAX as usize) {
+        Ok(b) => b,
+        Err(e) => {
+          eprintln!(
+            "couldn't save cached answer to {:?}: {}",
+            message.questions, e
+          );
+          continue;
+        }
+      };
+      out.extend_from_slice(&stored.to_be_bytes());
+      out.extend_from_slice(&(stored + entry.ttl as u64).to_be_bytes());
+      out.push(view.len() as u8);
+      out.extend_from_slice(view.as_bytes());
+      out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+      out.extend_from_slice(&bytes);
+      count += 1;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, &out)?;
+    fs::rename(&tmp, path)?;
+    Ok(count)
+  }
+
+  /// Fill the cache from a snapshot written by `save`, skipping anything that's
+  /// expired since. Snapshots from a different version of the format are ignored
+  /// rather than guessed at.
+  pub fn load(&self, path: &Path) -> Result<usize, DnsError> {
+    if self.max_entries == 0 {
+      return Ok(0);
+    }
+    let data = fs::read(path)?;
+    let header = SNAPSHOT_MAGIC.len() + 2;
+    if data.len() < header || &data[..SNAPSHOT_MAGIC.len()] != SNAPSHOT_MAGIC {
+      return Err("not a cache snapshot".into());
+    }
+    let version = u16::from_be_bytes(data[SNAPSHOT_MAGIC.len()..header].try_into()?);
+    if version != SNAPSHOT_VERSION {
+      return Err(
+        format!(
+          "cache snapshot is version {}, we only read version {}",
+          version, SNAPSHOT_VERSION
+        )
+        .as_str()
+        .into(),
+      );
+    }
+
+    let now = Instant::now();
+    let now_unix = unix_time();
+    let mut count = 0;
+    let mut pos = header;
+    while pos < data.len() {
+      if data.len() < pos + 17 {
+        return Err("cache snapshot is cut short".into());
+      }
+      let stored = u64::from_be_bytes(data[pos..pos + 8].try_into()?);
+      let expires = u64::from_be_bytes(data[pos + 8..pos + 16].try_into()?);
+      let view_len = data[pos + 16] as usize;
+      pos += 17;
+      if data.len() < pos + view_len + 2 {
+        return Err("cache snapshot is cut short".into());
+      }
+      let view = String::from_utf8_lossy(&data[pos..pos + view_len]).to_string();
+      pos += view_len;
+      let len = u16::from_be_bytes(data[pos..pos + 2].try_into()?) as usize;
+      pos += 2;
+      if data.len() < pos + len {
+        return Err("cache snapshot is cut short".into());
+      }
+      let bytes = &data[pos..pos + len];
+      pos += len;
+
+      if expires <= now_unix || stored > now_unix {
+        continue;
+      }
+      let stored_at = match now.checked_sub(Duration::from_secs(now_unix - stored)) {
+        Some(s) => s,
+        None => continue,
+      };
+      let mut message = DnsMessage::default();
+      if let Err(e) = message.parse(bytes) {
+        eprintln!("skipping garbled cache entry: {}", e);
+        continue;
+      }
+      if message.questions.len() != 1 || message.flags.truncated {
+        continue;
+      }
+      let question = message.questions.remove(0);
+      // the limits might have changed since the snapshot was taken
+      let ttl = match self.ttl_of(&mut message) {
+        Some(ttl) if ttl > 0 => ttl.min(u32::try_from(expires - stored).unwrap_or(u32::MAX)),
+        _ => continue,
+      };
+      self.put((view, question), message, stored_at, ttl);
+      count += 1;
+    }
+    Ok(count)
+  }
+
+  /// How long `answer` can be cached for, with its ttls held inside our limits, or
+  /// None if it's not an answer we keep
+  fn ttl_of(&self, answer: &mut DnsMessage) -> Option<u32> {
+    match answer.flags.rcode {
+      ResultCode::NOERROR if !answer.answers.is_empty() => Some(self.clamp(answer)),
+      ResultCode::NOERROR | ResultCode::NXDOMAIN => self.negative_ttl(answer),
+      _ => None,
+    }
+  }
+
+  /// How long a negative answer is good for (RFC 2308 section 5): the lesser of the
+  /// SOA's own ttl and its minimum field, within our limits. No SOA, no caching.
+  ///
+  /// The SOA's ttl is set to that, and nothing else in the answer is allowed to
+  /// outlive it.
+  fn negative_ttl(&self, answer: &mut DnsMessage) -> Option<u32> {
+    let ttl = answer.authorities.iter().find_map(|r| match r {
+      DnsRecord::SOA { ttl, minimum, .. } => Some((*ttl).min(*minimum)),
+      _ => None,
+    })?;
+    let ttl = ttl.clamp(self.min_ttl, self.negative_max_ttl);
+    for r in answer.authorities.iter_mut() {
+      if let DnsRecord::SOA { .. } = r {
+        r.set_ttl(ttl);
+      }
+    }
+    set_ttls(answer, |t| t.min(ttl));
+    Some(ttl)
+  }
+
+  /// Hold every record's ttl inside our configured limits, and give back the
+  /// smallest of them, which is how long the whole answer is good for.
+  fn clamp(&self, answer: &mut DnsMessage) -> u32 {
+    let mut lowest = self.max_ttl;
+    set_ttls(answer, |ttl| {
+      let ttl = ttl.clamp(self.min_ttl, self.max_ttl);
+      lowest = lowest.min(ttl);
+      ttl
+    });
+    lowest
+  }
+}
+
+/// Seconds since the epoch; snapshots need times that mean something after a restart
+fn unix_time() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0)
+}
+
+impl Entries {
+  fn remove(&mut self, key: &Key) -> Option<Entry> {
+    let entry = self.map.remove(key)?;
+    self.lru.remove(&entry.last_used);
+    Some(entry)
+  }
+
+  /// Move an entry to the back of the lru order, giving back its new position
+  fn touch(&mut self, key: &Key, last_used: u64) -> u64 {
+    self.lru.remove(&last_used);
+    self.tick += 1;
+    self.lru.insert(self.tick, key.clone());
+    self.tick
+  }
+}
+
+/// Rewrite the ttl of every record in every section of `answer`
+fn set_ttls(answer: &mut DnsMessage, mut f: impl FnMut(u32) -> u32) {
+  for r in answer
+    .answers
+    .iter_mut()
+    .chain(answer.authorities.iter_mut())
+    .chain(answer.additionals.iter_mut())
+  {
+    r.set_ttl(f(r.ttl()));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::dnsmessage::{DnsRecord, QueryType};
+  use std::{
+    env,
+    net::Ipv4Addr,
+    process,
+    sync::{mpsc, Arc},
+    thread,
+  };
+
+  fn answer(name: &str, ttl: u32) -> DnsMessage {
+    DnsMessage {
+      answers: vec![DnsRecord::A {
+        domain: name.to_string(),
+        addr: Ipv4Addr::new(10, 0, 0, 1),
+        ttl,
+      }],
+      ..DnsMessage::default()
+    }
+  }
+
+  fn negative(rcode: ResultCode, soa_ttl: u32, minimum: u32) -> DnsMessage {
+    let mut message = DnsMessage {
+      authorities: vec![DnsRecord::SOA {
+        domain: "example.test".into(),
+        m_name: "ns1.example.test".into(),
+        r_name: "hostmaster.example.test".into(),
+        serial: 1,
+        refresh: 2,
+        retry: 3,
+        expire: 4,
+        minimum,
+        ttl: soa_ttl,
+      }],
+      ..DnsMessage::default()
+    };
+    message.flags.rcode = rcode;
+    message
+  }
+
+  #[test]
+  fn least_recently_used_goes_first() {
+    let cache = Cache::new(&Config::with(|c| c.cache_size = 2));
+    for name in ["a.test", "b.test"] {
+      cache.insert(
+        "",
+        &DnsQuestion::new(name, QueryType::A),
+        &mut answer(name, 300),
+      );
+    }
+    // a is used, so b is the one that goes when c comes in
+    let a = DnsQuestion::new("a.test", QueryType::A);
+    assert!(cache.get("", &a).is_some());
+    cache.insert(
+      "",
+      &DnsQuestion::new("c.test", QueryType::A),
+      &mut answer("c.test", 300),
+    );
+    assert!(cache.get("", &a).is_some());
+    assert!(cache
+      .get("", &DnsQuestion::new("b.test", QueryType::A))
+      .is_none());
+    assert!(cache
+      .get("", &DnsQuestion::new("c.test", QueryType::A))
+      .is_some());
+  }
+
+  #[test]
+  fn answers_are_kept_per_question() {
+    let cache = Cache::new(&Config::default().unwrap());
+    cache.insert(
+      "",
+      &DnsQuestion::new("a.test", QueryType::A),
+      &mut answer("a.test", 300),
+    );
+    let hit = cache
+      .get("", &DnsQuestion::new("a.test", QueryType::A))
+      .unwrap();
+    assert_eq!(hit.answer.answers, answer("a.test", 300).answers);
+    assert!(!hit.prefetch);
+    assert!(cache
+      .get("", &DnsQuestion::new("a.test", QueryType::AAAA))
+      .is_none());
+  }
+
+  #[test]
+  fn views_keep_their_own_answers() {
+    let cache = Cache::new(&Config::default().unwrap());
+    let question = DnsQuestion::new("a.test", QueryType::A);
+    cache.insert("lan", &question, &mut answer("a.test", 300));
+    assert!(cache.get("lan", &question).is_some());
+    assert!(cache.get("", &question).is_none());
+    assert!(cache.get("guest", &question).is_none());
+  }
+
+  #[test]
+  fn ttls_are_clamped_in_place() {
+    let cache = Cache::new(&Config::with(|c| {
+      c.cache_min_ttl = 60;
+      c.cache_max_ttl = 600;
+    }));
+    let mut low = answer("low.test", 5);
+    cache.insert("", &DnsQuestion::new("low.test", QueryType::A), &mut low);
+    assert_eq!(low.answers[0].ttl(), 60);
+    let high = DnsQuestion::new("high.test", QueryType::A);
+    let mut answer = answer("high.test", 4_000_000_000);
+    cache.insert("", &high, &mut answer);
+    assert_eq!(answer.answers[0].ttl(), 600);
+    let hit = cache.get("", &high).unwrap();
+    assert!(hit.answer.answers[0].ttl() <= 600);
+  }
+
+  #[test]
+  fn some_answers_arent_kept() {
+    let question = DnsQuestion::new("a.test", QueryType::A);
+    // nothing's kept at all without any room
+    let none = Cache::new(&Config::with(|c| c.cache_size = 0));
+    none.insert("", &question, &mut answer("a.test", 300));
+    assert!(none.get("", &question).is_none());
+
+    let cache = Cache::new(&Config::default().unwrap());
+
+    let mut truncated = answer("a.test", 300);
+    truncated.flags.truncated = true;
+    cache.insert("", &question, &mut truncated);
+    assert!(cache.get("", &question).is_none());
+
+    cache.insert("", &question, &mut answer("a.test", 0));
+    assert!(cache.get("", &question).is_none());
+
+    let mut servfail = DnsMessage::default();
+    servfail.flags.rcode = ResultCode::SERVFAIL;
+    cache.insert("", &question, &mut servfail);
+    assert!(cache.get("", &question).is_none());
+  }
+
+  #[test]
+  fn negative_answers_last_as_long_as_their_soa_says() {
+    let cache = Cache::new(&Config::with(|c| c.cache_negative_max_ttl = 100));
+    let nx_question = DnsQuestion::new("nx.example.test", QueryType::A);
+    let mut nx = negative(ResultCode::NXDOMAIN, 3600, 900);
+    cache.insert("", &nx_question, &mut nx);
+    // the lesser of the SOA's ttl and minimum, within our own limit
+    assert_eq!(nx.authorities[0].ttl(), 100);
+    let hit = cache.get("", &nx_question).unwrap();
+    assert_eq!(hit.answer.flags.rcode, ResultCode::NXDOMAIN);
+    assert_eq!(hit.answer.authorities, nx.authorities);
+
+    // NODATA is a NOERROR with nothing in it
+    let nodata_question = DnsQuestion::new("www.example.test", QueryType::AAAA);
+    let mut nodata = negative(ResultCode::NOERROR, 30, 900);
+    cache.insert("", &nodata_question, &mut nodata);
+    assert_eq!(nodata.authorities[0].ttl(), 30);
+    let hit = cache.get("", &nodata_question).unwrap();
+    assert!(hit.answer.answers.is_empty());
+  }
+
+  #[test]
+  fn negative_answers_need_an_soa() {
+    let cache = Cache::new(&Config::default().unwrap());
+    let question = DnsQuestion::new("nx.example.test", QueryType::A);
+    let mut bare = DnsMessage::default();
+    bare.flags.rcode = ResultCode::NXDOMAIN;
+    cache.insert("", &question, &mut bare);
+    assert!(cache.get("", &question).is_none());
+
+    cache.insert("", &question, &mut DnsMessage::default());
+    assert!(cache.get("", &question).is_none());
+  }
+
+  #[test]
+  fn popular_entries_near_expiry_are_prefetched_one_at_a_time() {
+    let cache = Cache::new(&Config::with(|c| c.cache_prefetch_hits = 2));
+    let question = DnsQuestion::new("a.test", QueryType::A);
+    // stored 95 seconds ago and good for 100, so it's in the last tenth of its life
+    let stored = Instant::now() - Duration::from_secs(95);
+    let key = (String::new(), question.clone());
+    cache.put(key, answer("a.test", 100), stored, 100);
+
+    assert!(!cache.get("", &question).unwrap().prefetch);
+    assert!(cache.get("", &question).unwrap().prefetch);
+    // one's already on its way
+    assert!(!cache.get("", &question).unwrap().prefetch);
+    assert!(!cache.start_refresh("", &question));
+    // that one fell through, so the next hit can try again
+    cache.end_refresh("", &question);
+    assert!(cache.get("", &question).unwrap().prefetch);
+  }
+
+  #[test]
+  fn huge_ttls_dont_overflow_prefetch() {
+    let cache = Cache::new(&Config::with(|c| {
+      c.cache_max_ttl = u32::MAX;
+      c.cache_prefetch_hits = 1;
+    }));
+    let question = DnsQuestion::new("big.test", QueryType::A);
+    cache.insert("", &question, &mut answer("big.test", u32::MAX));
+    assert!(!cache.get("", &question).unwrap().prefetch);
+  }
+
+  #[test]
+  fn expired_answers_are_stale_for_a_while() {
+    let cache = Cache::new(&Config::with(|c| {
+      c.cache_stale_window = 100;
+      c.cache_stale_ttl = 30;
+    }));
+    let stale = DnsQuestion::new("stale.test", QueryType::A);
+    let gone = DnsQuestion::new("gone.test", QueryType::A);
+    let now = Instant::now();
+    cache.put(
+      (String::new(), stale.clone()),
+      answer("stale.test", 300),
+      now - Duration::from_secs(350),
+      300,
+    );
+    cache.put(
+      (String::new(), gone.clone()),
+      answer("gone.test", 300),
+      now - Duration::from_secs(450),
+      300,
+    );
+
+    assert!(cache.get("", &stale).is_none());
+    let served = cache.get_stale("", &stale).unwrap();
+    assert_eq!(served.answers[0].ttl(), 30);
+    assert!(cache.get_stale("", &gone).is_none());
+
+    // only one lookup gets to go upstream for it at a time
+    assert!(cache.start_refresh("", &stale));
+    assert!(!cache.start_refresh("", &stale));
+    cache.end_refresh("", &stale);
+    assert!(cache.start_refresh("", &stale));
+    // a fresh answer replaces the entry, and nobody's refreshing that one
+    cache.insert("", &stale, &mut answer("stale.test", 300));
+    assert!(cache.start_refresh("", &stale));
+  }
+
+  fn owner(claim: Claim) -> LookupOwner {
+    match claim {
+      Claim::Owner(owner) => owner,
+      Claim::Waiter(_) => panic!("someone else has the lookup"),
+    }
+  }
+
+  fn waiter(claim: Claim) -> Arc<Lookup> {
+    match claim {
+      Claim::Owner(_) => panic!("nobody else has the lookup"),
+      Claim::Waiter(lookup) => lookup,
+    }
+  }
+
+  #[test]
+  fn misses_wait_on_the_lookup_already_going() {
+    let cache = Arc::new(Cache::new(&Config::default().unwrap()));
+    let question = DnsQuestion::new("a.test", QueryType::A);
+    let first = owner(cache.start_lookup("", &question));
+    // other views ask for themselves
+    let _lan = owner(cache.start_lookup("lan", &question));
+    let waiters: Vec<_> = (0..3)
+      .map(|_| {
+        let lookup = waiter(cache.start_lookup("", &question));
+        thread::spawn(move || lookup.wait(Duration::from_secs(5)))
+      })
+      .collect();
+    first.end(Some(&answer("a.test", 300)));
+    for waiter in waiters {
+      assert_eq!(
+        waiter.join().unwrap().unwrap().answers,
+        answer("a.test", 300).answers
+      );
+    }
+
+    // the next miss goes upstream again, and a failure is passed on as one
+    let second = owner(cache.start_lookup("", &question));
+    let lookup = waiter(cache.start_lookup("", &question));
+    second.end(None);
+    assert!(lookup.wait(Duration::from_secs(5)).is_none());
+  }
+
+  #[test]
+  fn lookups_that_die_or_stall_let_their_waiters_go() {
+    let cache = Arc::new(Cache::new(&Config::default().unwrap()));
+    let question = DnsQuestion::new("a.test", QueryType::A);
+    let lookup = {
+      let cache = cache.clone();
+      let question = question.clone();
+      let (tx, rx) = mpsc::channel();
+      let upstream = thread::spawn(move || {
+        let _owner = owner(cache.start_lookup("", &question));
+        tx.send(waiter(cache.start_lookup("", &question))).unwrap();
+        panic!("upstream lookup blew up");
+      });
+      assert!(upstream.join().is_err());
+      rx.recv().unwrap()
+    };
+    assert!(lookup.wait(Duration::from_secs(5)).is_none());
+    // and the next miss gets to go upstream itself
+    let stalled = owner(cache.start_lookup("", &question));
+    let lookup = waiter(cache.start_lookup("", &question));
+    let started = Instant::now();
+    assert!(lookup.wait(Duration::from_millis(50)).is_none());
+    assert!(started.elapsed() >= Duration::from_millis(50));
+    drop(stalled);
+  }
+
+  #[test]
+  fn snapshots_round_trip() {
+    let path = env::temp_dir().join(format!("dinosaurus-cache-{}", process::id()));
+    let a = DnsQuestion::new("a.test", QueryType::A);
+    let b = DnsQuestion::new("b.test", QueryType::A);
+    let before = Cache::new(&Config::default().unwrap());
+    before.insert("", &a, &mut answer("a.test", 300));
+    before.insert("lan", &b, &mut answer("b.test", 300));
+    assert_eq!(before.save(&path).unwrap(), 2);
+
+    // the limits have come down since, and loaded answers have to respect that
+    let after = Cache::new(&Config::with(|c| c.cache_max_ttl = 30));
+    assert_eq!(after.load(&path).unwrap(), 2);
+    let hit = after.get("", &a).unwrap();
+    assert!(hit.answer.answers[0].ttl() <= 30);
+    assert!(after.get("lan", &b).is_some());
+    assert!(after.get("", &b).is_none());
+
+    fs::write(&path, b"not a snapshot").unwrap();
+    assert!(after.load(&path).is_err());
+    let mut other_version = SNAPSHOT_MAGIC.to_vec();
+    other_version.extend_from_slice(&(SNAPSHOT_VERSION + 1).to_be_bytes());
+    fs::write(&path, other_version).unwrap();
+    assert!(after.load(&path).is_err());
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn saves_dont_trip_over_each_other() {
+    let path = env::temp_dir().join(format!("dinosaurus-cache-race-{}", process::id()));
+    let cache = Arc::new(Cache::new(&Config::default().unwrap()));
+    let question = DnsQuestion::new("a.test", QueryType::A);
+    cache.insert("", &question, &mut answer("a.test", 300));
+    let savers: Vec<_> = (0..4)
+      .map(|_| {
+        let cache = cache.clone();
+        let path = path.clone();
+        thread::spawn(move || (0..50).all(|_| cache.save(&path).is_ok()))
+      })
+      .collect();
+    for saver in savers {
+      assert!(saver.join().unwrap());
+    }
+    assert_eq!(
+      Cache::new(&Config::default().unwrap()).load(&path).unwrap(),
+      1
+    );
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn the_save_that_writes_last_has_the_latest_answers() {
+    let path = env::temp_dir().join(format!("dinosaurus-cache-order-{}", process::id()));
+    let cache = Arc::new(Cache::new(&Config::default().unwrap()));
+    let question = DnsQuestion::new("a.test", QueryType::A);
+    cache.insert("", &question, &mut answer("a.test", 300));
+
+    // a save that has to wait its turn while the answer changes underneath it
+    let waiting = {
+      let _other_save = cache.save_lock.lock().unwrap();
+      let waiting = {
+        let (cache, path) = (cache.clone(), path.clone());
+        thread::spawn(move || cache.save(&path).unwrap())
+      };
+      thread::sleep(Duration::from_millis(100));
+      let mut new = DnsMessage {
+        answers: vec![DnsRecord::A {
+          domain: "a.test".into(),
+          addr: Ipv4Addr::new(10, 0, 0, 2),
+          ttl: 300,
+        }],
+        ..DnsMessage::default()
+      };
+      cache.insert("", &question, &mut new);
+      waiting
+    };
+    assert_eq!(waiting.join().unwrap(), 1);
+
+    let loaded = Cache::new(&Config::default().unwrap());
+    assert_eq!(loaded.load(&path).unwrap(), 1);
+    match &loaded.get("", &question).unwrap().answer.answers[0] {
+      DnsRecord::A { addr, .. } => assert_eq!(*addr, Ipv4Addr::new(10, 0, 0, 2)),
+      r => panic!("expected an A record, got {:?}", r),
+    }
+    fs::remove_file(&path).unwrap();
+  }
+}
@@ -1,29 +1,122 @@
-use crate::dnserror::DnsError;
+use crate::{
+  blocklist::BlockAction,
+  dnserror,
+  dnsmessage::normalize_name,
+  upstream::{ForwardRule, Strategy, Upstream},
+  view::{Network, ViewConfig},
+  zone::ZoneFile,
+};
 use std::{
+  fmt::Debug,
   fs::File,
-  io::{BufRead, BufReader, Error, Read},
-  net::{SocketAddr, SocketAddrV4},
+  io::{BufRead, BufReader},
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4},
   path::Path,
   path::PathBuf,
+  str::FromStr,
+  time::Duration,
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct Config {
   pub interface: String,
   pub ip_address: SocketAddrV4,
   pub config_location: PathBuf,
+  // seconds a tcp connection can sit without sending us a query before we hang up
+  pub tcp_idle_timeout: u64,
+  // most tcp connections we'll hold open at once; past that, new ones are closed
+  pub tcp_max_connections: usize,
+  // threads taking udp queries upstream; everything else is answered as it comes in
+  pub udp_workers: usize,
+  // most udp queries we'll hold waiting on a worker; past that, new ones are dropped
+  pub udp_max_queries: usize,
+  // resolvers we hand queries off to when we can't answer them ourselves
+  pub upstreams: Vec<Upstream>,
+  pub upstream_strategy: Strategy,
+  // milliseconds to wait on an upstream that doesn't have its own timeout
+  pub upstream_timeout: u64,
+  // how many tries a single query gives each upstream before moving on to the next
+  pub upstream_attempts: usize,
+  // consecutive failures before an upstream is marked down
+  pub upstream_max_failures: u32,
+  // seconds between checks on upstreams that are marked down
+  pub upstream_probe_interval: u64,
+  // names under these suffixes go to their own upstreams instead
+  pub forward_rules: Vec<ForwardRule>,
+  // most answers we'll cache at once; 0 turns the cache off
+  pub cache_size: usize,
+  // seconds; ttls from upstream are held between these while cached
+  pub cache_min_ttl: u32,
+  pub cache_max_ttl: u32,
+  // seconds; NXDOMAIN and NODATA answers are never cached for longer than this
+  pub cache_negative_max_ttl: u32,
+  // seconds past expiry an answer can still be served if upstreams don't answer
+  pub cache_stale_window: u64,
+  // ttl, in seconds, of answers served stale
+  pub cache_stale_ttl: u32,
+  // milliseconds to wait on upstream before serving a stale answer instead, while
+  // the lookup carries on in the background (RFC 8767's client response timer)
+  pub cache_stale_answer_timeout: u64,
+  // hits an answer needs to be refreshed before it expires; 0 turns prefetch off
+  pub cache_prefetch_hits: u32,
+  // where to keep the cache across restarts, if anywhere
+  pub cache_file: Option<PathBuf>,
+  // seconds between cache snapshots, besides the one at shutdown; 0 for none
+  pub cache_save_interval: u64,
+  // zones we answer for ourselves, from master files
+  pub zones: Vec<ZoneFile>,
+  // hosts-format files whose names we answer for before anything else
+  pub hosts_files: Vec<PathBuf>,
+  // ttl, in seconds, of answers from the hosts files
+  pub hosts_ttl: u32,
+  // split horizon: each client gets the first view with a network it's in
+  pub views: Vec<ViewConfig>,
+  // lists of names (and everything under them) we won't resolve
+  pub blocklists: Vec<PathBuf>,
+  // what clients get back for a blocked name
+  pub block_action: BlockAction,
+  // ttl, in seconds, of sinkhole answers for blocked names
+  pub block_ttl: u32,
 }
 
 impl Config {
-  pub(crate) fn default() -> Result<Config, DnsError> {
+  pub(crate) fn default() -> dnserror::Result<Config> {
     Ok(Config {
       interface: "du0".to_string(),
       ip_address: "0.0.0.0:5354".parse::<std::net::SocketAddrV4>().unwrap(),
       config_location: Path::new(".").to_owned(),
+      tcp_idle_timeout: 10,
+      tcp_max_connections: 100,
+      udp_workers: 16,
+      udp_max_queries: 500,
+      upstreams: Vec::new(),
+      upstream_strategy: Strategy::Failover,
+      upstream_timeout: 2000,
+      upstream_attempts: 2,
+      upstream_max_failures: 3,
+      upstream_probe_interval: 30,
+      forward_rules: Vec::new(),
+      cache_size: 10000,
+      cache_min_ttl: 0,
+      cache_max_ttl: 86400,
+      cache_negative_max_ttl: 3600,
+      cache_stale_window: 86400,
+      cache_stale_ttl: 30,
+      cache_stale_answer_timeout: 1800,
+      cache_prefetch_hits: 3,
+      cache_file: None,
+      cache_save_interval: 300,
+      zones: Vec::new(),
+      hosts_files: Vec::new(),
+      hosts_ttl: 300,
+      views: Vec::new(),
+      blocklists: Vec::new(),
+      block_action: BlockAction::Sinkhole(Some(Ipv4Addr::UNSPECIFIED), Some(Ipv6Addr::UNSPECIFIED)),
+      block_ttl: 60,
     })
   }
-  pub(crate) fn load(f: String) -> std::io::Result<Config> {
-    let mut config = Self::default().unwrap();
+  pub(crate) fn load(f: String) -> dnserror::Result<Config> {
+    let mut config = Self::default()?;
     let path = PathBuf::from(f.clone());
     let file = File::open(&path)?;
     let buf_reader = BufReader::new(file);
@@ -31,19 +124,12 @@ impl Config {
       match line {
         Ok(l) => {
           if l.starts_with("interface") {
-            let line_elements: Vec<_> = l.split(' ').collect();
-            config.interface = line_elements
-              .last()
-              .unwrap()
-              .trim()
-              .replace('\"', "")
-              .to_string();
+            config.interface = value_of(&l);
           }
           if l.starts_with("ip_address") {
-            let line_elements: Vec<_> = l.split(' ').collect();
             // we get something like "\"172.16.35.1:5354\"" so we have to strip the double quote
             // characters here (and .trim() removes newlines)
-            let ip = line_elements.last().unwrap().trim().replace('\"', "");
+            let ip = value_of(&l);
             config.ip_address = match ip.parse::<std::net::SocketAddrV4>() {
               Ok(i) => i,
               Err(e) => {
@@ -55,11 +141,314 @@ impl Config {
               }
             }
           }
+          if l.starts_with("tcp_idle_timeout") {
+            config.tcp_idle_timeout = parse_value(&l, config.tcp_idle_timeout);
+          }
+          if l.starts_with("tcp_max_connections") {
+            config.tcp_max_connections = parse_value(&l, config.tcp_max_connections);
+          }
+          if l.starts_with("udp_workers") {
+            config.udp_workers = parse_value(&l, config.udp_workers);
+          }
+          if l.starts_with("udp_max_queries") {
+            config.udp_max_queries = parse_value(&l, config.udp_max_queries);
+          }
+          if l.starts_with("upstream ") {
+            let value = value_of(&l);
+            match parse_upstream(&value) {
+              Some(u) => config.upstreams.push(u),
+              None => eprintln!("error parsing upstream {:?}! skipping it...", value),
+            }
+          }
+          if l.starts_with("upstream_strategy") {
+            config.upstream_strategy = parse_value(&l, config.upstream_strategy);
+          }
+          if l.starts_with("upstream_timeout") {
+            config.upstream_timeout = parse_value(&l, config.upstream_timeout);
+          }
+          if l.starts_with("upstream_attempts") {
+            config.upstream_attempts = parse_value(&l, config.upstream_attempts);
+          }
+          if l.starts_with("upstream_max_failures") {
+            config.upstream_max_failures = parse_value(&l, config.upstream_max_failures);
+          }
+          if l.starts_with("upstream_probe_interval") {
+            config.upstream_probe_interval = parse_value(&l, config.upstream_probe_interval);
+          }
+          if l.starts_with("forward ") {
+            let value = value_of(&l);
+            match parse_forward_rule(&value) {
+              Some(r) => config.forward_rules.push(r),
+              None => eprintln!("error parsing forward rule {:?}! skipping it...", value),
+            }
+          }
+          if l.starts_with("cache_size") {
+            config.cache_size = parse_value(&l, config.cache_size);
+          }
+          if l.starts_with("cache_min_ttl") {
+            config.cache_min_ttl = parse_value(&l, config.cache_min_ttl);
+          }
+          if l.starts_with("cache_max_ttl") {
+            config.cache_max_ttl = parse_value(&l, config.cache_max_ttl);
+          }
+          if l.starts_with("cache_negative_max_ttl") {
+            config.cache_negative_max_ttl = parse_value(&l, config.cache_negative_max_ttl);
+          }
+          if l.starts_with("cache_stale_window") {
+            config.cache_stale_window = parse_value(&l, config.cache_stale_window);
+          }
+          if l.starts_with("cache_stale_ttl") {
+            config.cache_stale_ttl = parse_value(&l, config.cache_stale_ttl);
+          }
+          if l.starts_with("cache_stale_answer_timeout") {
+            config.cache_stale_answer_timeout = parse_value(&l, config.cache_stale_answer_timeout);
+          }
+          if l.starts_with("cache_prefetch_hits") {
+            config.cache_prefetch_hits = parse_value(&l, config.cache_prefetch_hits);
+          }
+          if l.starts_with("cache_file") {
+            let value = value_of(&l);
+            config.cache_file = if value.is_empty() {
+              None
+            } else {
+              Some(PathBuf::from(value))
+            };
+          }
+          if l.starts_with("cache_save_interval") {
+            config.cache_save_interval = parse_value(&l, config.cache_save_interval);
+          }
+          if l.starts_with("zone ") {
+            let value = value_of(&l);
+            match parse_zone_file(&value) {
+              Some(z) => config.zones.push(z),
+              None => eprintln!("error parsing zone {:?}! skipping it...", value),
+            }
+          }
+          if l.starts_with("hosts_file") {
+            config.hosts_files.push(PathBuf::from(value_of(&l)));
+          }
+          if l.starts_with("hosts_ttl") {
+            config.hosts_ttl = parse_value(&l, config.hosts_ttl);
+          }
+          if l.starts_with("view ") {
+            let value = value_of(&l);
+            match parse_view(&value) {
+              Some(v) if config.views.iter().any(|o| o.name == v.name) => {
+                eprintln!("view {:?} is declared twice! skipping it...", v.name)
+              }
+              Some(v) => config.views.push(v),
+              None => eprintln!("error parsing view {:?}! skipping it...", value),
+            }
+          }
+          if l.starts_with("view_zone") {
+            let value = value_of(&l);
+            match view_setting(&mut config.views, &value) {
+              Some((view, rest)) => match parse_zone_file(rest) {
+                Some(z) => view.zones.push(z),
+                None => eprintln!("error parsing view zone {:?}! skipping it...", value),
+              },
+              None => eprintln!("no view declared for {:?}! skipping it...", value),
+            }
+          }
+          if l.starts_with("view_forward") {
+            let value = value_of(&l);
+            match view_setting(&mut config.views, &value) {
+              Some((view, rest)) => match parse_forward_rule(rest) {
+                Some(r) => view.forward_rules.push(r),
+                None => eprintln!(
+                  "error parsing view forward rule {:?}! skipping it...",
+                  value
+                ),
+              },
+              None => eprintln!("no view declared for {:?}! skipping it...", value),
+            }
+          }
+          if l.starts_with("view_blocklist") {
+            let value = value_of(&l);
+            match view_setting(&mut config.views, &value) {
+              Some((view, path)) if !path.is_empty() => view.blocklists.push(PathBuf::from(path)),
+              Some(_) => eprintln!("no blocklist given in {:?}! skipping it...", value),
+              None => eprintln!("no view declared for {:?}! skipping it...", value),
+            }
+          }
+          if l.starts_with("view_recursion") {
+            let value = value_of(&l);
+            match view_setting(&mut config.views, &value) {
+              Some((view, "yes")) => view.recursion = true,
+              Some((view, "no")) => view.recursion = false,
+              Some(_) => eprintln!(
+                "view_recursion takes yes or no, not {:?}! skipping it...",
+                value
+              ),
+              None => eprintln!("no view declared for {:?}! skipping it...", value),
+            }
+          }
+          if l.starts_with("blocklist") {
+            config.blocklists.push(PathBuf::from(value_of(&l)));
+          }
+          if l.starts_with("block_action") {
+            config.block_action = parse_value(&l, config.block_action);
+          }
+          if l.starts_with("block_ttl") {
+            config.block_ttl = parse_value(&l, config.block_ttl);
+          }
         }
-        Err(_) => todo!(),
+        Err(e) => return Err(e.into()),
       }
     }
-    config.config_location = std::fs::canonicalize(path).unwrap();
+    config.config_location = std::fs::canonicalize(path)?;
     Ok(config)
   }
 }
+
+#[cfg(test)]
+impl Config {
+  /// The defaults, with whatever a test needs changed
+  pub(crate) fn with(f: impl FnOnce(&mut Config)) -> Config {
+    let mut config = Config::default().unwrap();
+    f(&mut config);
+    config
+  }
+}
+
+/// The value half of a `key = "value"` line, with the quotes and whitespace stripped
+fn value_of(line: &str) -> String {
+  let value = match line.split_once('=') {
+    Some((_, v)) => v,
+    None => line,
+  };
+  value.trim().replace('\"', "")
+}
+
+/// Parse the value of a line, keeping `default` (and complaining) if it's no good
+fn parse_value<T>(line: &str, default: T) -> T
+where
+  T: FromStr,
+  T::Err: Debug,
+{
+  match value_of(line).parse::<T>() {
+    Ok(v) => v,
+    Err(e) => {
+      eprintln!("error parsing {:?}! using default... error:{:#?}", line, e);
+      default
+    }
+  }
+}
+
+/// Upstreams can be given as `1.1.1.1:53`, `[2606:4700::1111]:53`, or a bare
+/// address, in which case we assume port 53. A number of milliseconds after a
+/// comma, like `10.0.0.53,500`, overrides `upstream_timeout` for that upstream. On
+/// an `upstream` line a space works as well as the comma.
+pub(crate) fn parse_upstream(value: &str) -> Option<Upstream> {
+  let mut fields = value
+    .split(|c: char| c == ',' || c.is_whitespace())
+    .filter(|f| !f.is_empty());
+  let addr = fields.next()?;
+  let addr = match addr.parse::<SocketAddr>() {
+    Ok(a) => a,
+    Err(_) => SocketAddr::new(addr.parse::<IpAddr>().ok()?, 53),
+  };
+  let timeout = match fields.next() {
+    Some(t) => Some(Duration::from_millis(t.parse::<u64>().ok()?)),
+    None => None,
+  };
+  if fields.next().is_some() {
+    return None;
+  }
+  Some(Upstream { addr, timeout })
+}
+
+/// A forward rule is a domain suffix followed by one or more upstreams, like
+/// `corp.internal 10.0.0.53 10.0.0.54:5353,500`, each written as for `upstream`
+/// but always with the comma before a timeout of its own. Use `.` for the suffix
+/// to match everything.
+pub(crate) fn parse_forward_rule(value: &str) -> Option<ForwardRule> {
+  let mut fields = value.split_whitespace();
+  let suffix = normalize_name(fields.next()?);
+  let upstreams = fields
+    .map(parse_upstream)
+    .collect::<Option<Vec<Upstream>>>()?;
+  if upstreams.is_empty() {
+    return None;
+  }
+  Some(ForwardRule { suffix, upstreams })
+}
+
+/// A zone is its origin and the master file to load it from, like
+/// `example.internal zones/example.internal.zone`
+pub(crate) fn parse_zone_file(value: &str) -> Option<ZoneFile> {
+  let mut fields = value.split_whitespace();
+  let origin = normalize_name(fields.next()?);
+  let path = PathBuf::from(fields.next()?);
+  if fields.next().is_some() {
+    return None;
+  }
+  Some(ZoneFile { origin, path })
+}
+
+/// A view is a name followed by the networks whose clients it's for, like
+/// `lan 192.168.0.0/16 10.0.0.0/8`
+pub(crate) fn parse_view(value: &str) -> Option<ViewConfig> {
+  let mut fields = value.split_whitespace();
+  let name = fields.next()?.to_string();
+  if name.len() > u8::MAX as usize {
+    return None;
+  }
+  let networks = fields
+    .map(|n| n.parse::<Network>().ok())
+    .collect::<Option<Vec<Network>>>()?;
+  if networks.is_empty() {
+    return None;
+  }
+  Some(ViewConfig::new(name, networks))
+}
+
+/// Settings for a view start with the view's name, which has to have been declared
+/// on an earlier line. Gives back the view and the rest of the setting.
+fn view_setting<'a>(
+  views: &'a mut [ViewConfig],
+  value: &'a str,
+) -> Option<(&'a mut ViewConfig, &'a str)> {
+  let (name, rest) = value.split_once(char::is_whitespace)?;
+  let view = views.iter_mut().find(|v| v.name == name)?;
+  Some((view, rest.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn upstreams_can_have_their_own_timeouts() {
+    let upstream = |addr: &str, timeout: Option<u64>| Upstream {
+      addr: addr.parse().unwrap(),
+      timeout: timeout.map(Duration::from_millis),
+    };
+    assert_eq!(
+      parse_upstream("10.0.0.53"),
+      Some(upstream("10.0.0.53:53", None))
+    );
+    assert_eq!(
+      parse_upstream("10.0.0.53:5353,500"),
+      Some(upstream("10.0.0.53:5353", Some(500)))
+    );
+    assert_eq!(
+      parse_upstream("fd00::53 250"),
+      Some(upstream("[fd00::53]:53", Some(250)))
+    );
+    assert_eq!(parse_upstream("10.0.0.53,soon"), None);
+    assert_eq!(parse_upstream("10.0.0.53,500,600"), None);
+
+    assert_eq!(
+      parse_forward_rule("Corp.Internal. 10.0.0.53,500 10.0.0.54:5353"),
+      Some(ForwardRule {
+        suffix: "corp.internal".into(),
+        upstreams: vec![
+          upstream("10.0.0.53:53", Some(500)),
+          upstream("10.0.0.54:5353", None),
+        ],
+      })
+    );
+    assert_eq!(parse_forward_rule("corp.internal"), None);
+  }
+}
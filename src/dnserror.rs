@@ -13,8 +13,8 @@ pub enum DnsError {
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum ErrorKind {
   ParseError { field: String },
-  ConfigError { field: String },
-  Other,
+  // a PacketBuf hit its limit; the message is fine, there's just no room for it
+  BufferFull,
 }
 
 impl From<&str> for DnsError {
@@ -31,9 +31,7 @@ impl From<io::Error> for DnsError {
 
 impl From<array::TryFromSliceError> for DnsError {
   fn from(err: array::TryFromSliceError) -> DnsError {
-    DnsError::Regular(ErrorKind::ConfigError {
-      field: "tx_id".to_string(),
-    })
+    DnsError::Convert(err)
   }
 }
 
@@ -48,9 +46,8 @@ impl From<Deserializer::Error> for DnsError {
 impl ErrorKind {
   fn as_str(&self) -> &str {
     match *self {
-      ErrorKind::ConfigError { field: _ } => "configuration error",
       ErrorKind::ParseError { field: _ } => "parse error",
-      ErrorKind::Other => "other error",
+      ErrorKind::BufferFull => "buffer full",
     }
   }
 }
@@ -58,7 +55,7 @@ impl ErrorKind {
 impl fmt::Display for DnsError {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     match *self {
-      DnsError::Regular(ref err) => write!(f, "{:?}", err),
+      DnsError::Regular(ref err) => write!(f, "{}: {:?}", err.as_str(), err),
       DnsError::Other(ref err) => write!(f, "{:?}", err),
       DnsError::Io(ref err) => err.fmt(f),
       DnsError::Convert(ref err) => err.fmt(f),
@@ -79,5 +76,4 @@ impl fmt::Display for DnsError {
 ///  _e,
 /// );
 /// ```
-
 pub type Result<T> = std::result::Result<T, DnsError>;
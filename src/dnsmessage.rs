@@ -1,14 +1,21 @@
-use crate::dnserror::DnsError;
-use std::net::Ipv4Addr;
+use crate::dnserror::{DnsError, ErrorKind};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
 //use bitlab::*;
-use std::convert::TryInto;
 use std::fmt;
 
 //type Error = Box<dyn std::error::Error>;
 //type Result<T> = std::result::Result<T, Error>;
 
-const HEADER_LEN: u8 = 12;
+pub(crate) const HEADER_LEN: u8 = 12;
+
+/// What every client can take over udp, edns or not (RFC 1035 4.2.1)
+pub(crate) const UDP_PAYLOAD_SIZE: usize = 512;
+
+/// What we advertise in our own OPT records, and the most we'll send over udp even
+/// if a client claims it can take more
+pub(crate) const EDNS_PAYLOAD_SIZE: u16 = 4096;
 
 /*
 *
@@ -94,12 +101,13 @@ const HEADER_LEN: u8 = 12;
 */
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
-#[allow(dead_code)]
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) enum DnsRecord {
   UNKNOWN {
     domain: String,
     qtype: u16,
-    data_len: u16,
+    class: u16,
+    data: Vec<u8>,
     ttl: u32,
   }, // 0
   A {
@@ -107,80 +115,893 @@ pub(crate) enum DnsRecord {
     addr: Ipv4Addr,
     ttl: u32,
   }, // 1
+  NS {
+    domain: String,
+    host: String,
+    ttl: u32,
+  }, // 2
+  CNAME {
+    domain: String,
+    host: String,
+    ttl: u32,
+  }, // 5
+  SOA {
+    domain: String,
+    m_name: String,
+    r_name: String,
+    serial: u32,
+    refresh: u32,
+    retry: u32,
+    expire: u32,
+    minimum: u32,
+    ttl: u32,
+  }, // 6
+  PTR {
+    domain: String,
+    host: String,
+    ttl: u32,
+  }, // 12
+  MX {
+    domain: String,
+    priority: u16,
+    host: String,
+    ttl: u32,
+  }, // 15
+  TXT {
+    domain: String,
+    data: Vec<Vec<u8>>,
+    ttl: u32,
+  }, // 16
+  AAAA {
+    domain: String,
+    addr: Ipv6Addr,
+    ttl: u32,
+  }, // 28
+  SRV {
+    domain: String,
+    priority: u16,
+    weight: u16,
+    port: u16,
+    host: String,
+    ttl: u32,
+  }, // 33
+  CAA {
+    domain: String,
+    flags: u8,
+    tag: String,
+    value: Vec<u8>,
+    ttl: u32,
+  }, // 257
 }
 
 impl DnsRecord {
+  pub fn ttl(&self) -> u32 {
+    match self {
+      DnsRecord::UNKNOWN { ttl, .. }
+      | DnsRecord::A { ttl, .. }
+      | DnsRecord::NS { ttl, .. }
+      | DnsRecord::CNAME { ttl, .. }
+      | DnsRecord::SOA { ttl, .. }
+      | DnsRecord::PTR { ttl, .. }
+      | DnsRecord::MX { ttl, .. }
+      | DnsRecord::TXT { ttl, .. }
+      | DnsRecord::AAAA { ttl, .. }
+      | DnsRecord::SRV { ttl, .. }
+      | DnsRecord::CAA { ttl, .. } => *ttl,
+    }
+  }
+
+  pub fn domain(&self) -> &str {
+    match self {
+      DnsRecord::UNKNOWN { domain, .. }
+      | DnsRecord::A { domain, .. }
+      | DnsRecord::NS { domain, .. }
+      | DnsRecord::CNAME { domain, .. }
+      | DnsRecord::SOA { domain, .. }
+      | DnsRecord::PTR { domain, .. }
+      | DnsRecord::MX { domain, .. }
+      | DnsRecord::TXT { domain, .. }
+      | DnsRecord::AAAA { domain, .. }
+      | DnsRecord::SRV { domain, .. }
+      | DnsRecord::CAA { domain, .. } => domain,
+    }
+  }
+
+  pub fn set_domain(&mut self, name: &str) {
+    match self {
+      DnsRecord::UNKNOWN { domain, .. }
+      | DnsRecord::A { domain, .. }
+      | DnsRecord::NS { domain, .. }
+      | DnsRecord::CNAME { domain, .. }
+      | DnsRecord::SOA { domain, .. }
+      | DnsRecord::PTR { domain, .. }
+      | DnsRecord::MX { domain, .. }
+      | DnsRecord::TXT { domain, .. }
+      | DnsRecord::AAAA { domain, .. }
+      | DnsRecord::SRV { domain, .. }
+      | DnsRecord::CAA { domain, .. } => *domain = name.to_string(),
+    }
+  }
+
+  pub fn qtype(&self) -> QueryType {
+    match self {
+      DnsRecord::UNKNOWN { qtype, .. } => QueryType::from_num(*qtype),
+      DnsRecord::A { .. } => QueryType::A,
+      DnsRecord::NS { .. } => QueryType::NS,
+      DnsRecord::CNAME { .. } => QueryType::CNAME,
+      DnsRecord::SOA { .. } => QueryType::SOA,
+      DnsRecord::PTR { .. } => QueryType::PTR,
+      DnsRecord::MX { .. } => QueryType::MX,
+      DnsRecord::TXT { .. } => QueryType::TXT,
+      DnsRecord::AAAA { .. } => QueryType::AAAA,
+      DnsRecord::SRV { .. } => QueryType::SRV,
+      DnsRecord::CAA { .. } => QueryType::CAA,
+    }
+  }
+
+  pub fn set_ttl(&mut self, new_ttl: u32) {
+    match self {
+      DnsRecord::UNKNOWN { ttl, .. }
+      | DnsRecord::A { ttl, .. }
+      | DnsRecord::NS { ttl, .. }
+      | DnsRecord::CNAME { ttl, .. }
+      | DnsRecord::SOA { ttl, .. }
+      | DnsRecord::PTR { ttl, .. }
+      | DnsRecord::MX { ttl, .. }
+      | DnsRecord::TXT { ttl, .. }
+      | DnsRecord::AAAA { ttl, .. }
+      | DnsRecord::SRV { ttl, .. }
+      | DnsRecord::CAA { ttl, .. } => *ttl = new_ttl,
+    }
+  }
+
   pub fn read(buffer: &mut PacketBuf) -> Result<DnsRecord, DnsError> {
     let mut domain = String::new();
     buffer.read_qname(&mut domain)?;
 
-    println!("{}", domain);
     let qtype_num = buffer.read_u16()?;
     let qtype = QueryType::from_num(qtype_num);
-    let _ = buffer.read_u16()?;
+    let class = buffer.read_u16()?;
     let ttl = buffer.read_u32()?;
     let data_len = buffer.read_u16()?;
-
-    match qtype {
+    let data_start = buffer.pos();
+
+    let record = match qtype {
+      // the typed records are all class IN. Anything else (CH TXT version.bind,
+      // say) is kept as it came, class and all, so it goes back out the same way
+      _ if class != 1 => DnsRecord::UNKNOWN {
+        data: foreign_rdata(buffer, qtype, data_len)?,
+        domain,
+        qtype: qtype_num,
+        class,
+        ttl,
+      },
       QueryType::A => {
         let raw_addr = buffer.read_u32()?;
         let addr = Ipv4Addr::new(
           ((raw_addr >> 24) & 0xFF) as u8,
           ((raw_addr >> 16) & 0xFF) as u8,
           ((raw_addr >> 8) & 0xFF) as u8,
-          ((raw_addr >> 0) & 0xFF) as u8,
+          (raw_addr & 0xFF) as u8,
         );
 
-        Ok(DnsRecord::A { domain, addr, ttl })
+        DnsRecord::A { domain, addr, ttl }
+      }
+      // these three carry nothing but a name, which may well point back into
+      // the rest of the packet, so they go through read_qname like any other
+      QueryType::NS => {
+        let mut host = String::new();
+        buffer.read_qname(&mut host)?;
+
+        DnsRecord::NS { domain, host, ttl }
+      }
+      QueryType::CNAME => {
+        let mut host = String::new();
+        buffer.read_qname(&mut host)?;
+
+        DnsRecord::CNAME { domain, host, ttl }
+      }
+      QueryType::SOA => {
+        let mut m_name = String::new();
+        buffer.read_qname(&mut m_name)?;
+        let mut r_name = String::new();
+        buffer.read_qname(&mut r_name)?;
+        let serial = buffer.read_u32()?;
+        let refresh = buffer.read_u32()?;
+        let retry = buffer.read_u32()?;
+        let expire = buffer.read_u32()?;
+        let minimum = buffer.read_u32()?;
+
+        DnsRecord::SOA {
+          domain,
+          m_name,
+          r_name,
+          serial,
+          refresh,
+          retry,
+          expire,
+          minimum,
+          ttl,
+        }
+      }
+      QueryType::PTR => {
+        let mut host = String::new();
+        buffer.read_qname(&mut host)?;
+
+        DnsRecord::PTR { domain, host, ttl }
       }
-      QueryType::UNKNOWN(_) => {
-        buffer.step(data_len as usize);
+      QueryType::MX => {
+        let priority = buffer.read_u16()?;
+        let mut host = String::new();
+        buffer.read_qname(&mut host)?;
 
-        Ok(DnsRecord::UNKNOWN {
+        DnsRecord::MX {
+          domain,
+          priority,
+          host,
+          ttl,
+        }
+      }
+      QueryType::SRV => {
+        let priority = buffer.read_u16()?;
+        let weight = buffer.read_u16()?;
+        let port = buffer.read_u16()?;
+        let mut host = String::new();
+        buffer.read_qname(&mut host)?;
+
+        DnsRecord::SRV {
+          domain,
+          priority,
+          weight,
+          port,
+          host,
+          ttl,
+        }
+      }
+      QueryType::AAAA => {
+        let raw_addr1 = buffer.read_u32()?;
+        let raw_addr2 = buffer.read_u32()?;
+        let raw_addr3 = buffer.read_u32()?;
+        let raw_addr4 = buffer.read_u32()?;
+        let addr = Ipv6Addr::new(
+          ((raw_addr1 >> 16) & 0xFFFF) as u16,
+          (raw_addr1 & 0xFFFF) as u16,
+          ((raw_addr2 >> 16) & 0xFFFF) as u16,
+          (raw_addr2 & 0xFFFF) as u16,
+          ((raw_addr3 >> 16) & 0xFFFF) as u16,
+          (raw_addr3 & 0xFFFF) as u16,
+          ((raw_addr4 >> 16) & 0xFFFF) as u16,
+          (raw_addr4 & 0xFFFF) as u16,
+        );
+
+        DnsRecord::AAAA { domain, addr, ttl }
+      }
+      QueryType::TXT => {
+        let mut data = Vec::new();
+        while buffer.pos() < data_start + data_len as usize {
+          let len = buffer.read()? as usize;
+          data.push(buffer.get_range(buffer.pos(), len)?.to_vec());
+          buffer.step(len)?;
+        }
+
+        DnsRecord::TXT { domain, data, ttl }
+      }
+      QueryType::CAA => {
+        let flags = buffer.read()?;
+        let tag_len = buffer.read()? as usize;
+        let tag = String::from_utf8_lossy(buffer.get_range(buffer.pos(), tag_len)?).to_string();
+        buffer.step(tag_len)?;
+        let value_len = (data_start + data_len as usize).saturating_sub(buffer.pos());
+        let value = buffer.get_range(buffer.pos(), value_len)?.to_vec();
+        buffer.step(value_len)?;
+
+        DnsRecord::CAA {
+          domain,
+          flags,
+          tag,
+          value,
+          ttl,
+        }
+      }
+      // we don't know what's in here, so we hang onto the raw bytes and hand them
+      // back out untouched (RFC 3597). OPT isn't a real record either; Edns
+      // picks it apart once the whole message has been read. Nobody should be
+      // sending ANY records, but if they do they get the same treatment.
+      QueryType::OPT | QueryType::ANY | QueryType::UNKNOWN(_) => {
+        let data = buffer.get_range(data_start, data_len as usize)?.to_vec();
+        buffer.step(data_len as usize)?;
+
+        DnsRecord::UNKNOWN {
           domain,
           qtype: qtype_num,
-          data_len,
+          class,
+          data,
           ttl,
-        })
+        }
+      }
+    };
+
+    // whatever we decoded has to have used up exactly the RDATA we were promised,
+    // otherwise we're out of step with the rest of the packet
+    if buffer.pos() != data_start + data_len as usize {
+      return Err(
+        format!(
+          "{} record data didn't match its length of {}",
+          qtype, data_len
+        )
+        .as_str()
+        .into(),
+      );
+    }
+
+    Ok(record)
+  }
+
+  /// Write the record into the buffer, returning how many bytes it took up
+  pub fn write(&self, buffer: &mut PacketBuf) -> Result<usize, DnsError> {
+    let start_pos = buffer.pos();
+
+    match *self {
+      DnsRecord::A {
+        ref domain,
+        ref addr,
+        ttl,
+      } => {
+        buffer.write_qname(domain)?;
+        buffer.write_u16(QueryType::A.to_num())?;
+        buffer.write_u16(1)?;
+        buffer.write_u32(ttl)?;
+        buffer.write_u16(4)?;
+
+        for octet in addr.octets() {
+          buffer.write_u8(octet)?;
+        }
+      }
+      DnsRecord::NS {
+        ref domain,
+        ref host,
+        ttl,
+      } => {
+        buffer.write_qname(domain)?;
+        buffer.write_u16(QueryType::NS.to_num())?;
+        buffer.write_u16(1)?;
+        buffer.write_u32(ttl)?;
+        buffer.write_rdata(|buffer| buffer.write_qname(host))?;
+      }
+      DnsRecord::CNAME {
+        ref domain,
+        ref host,
+        ttl,
+      } => {
+        buffer.write_qname(domain)?;
+        buffer.write_u16(QueryType::CNAME.to_num())?;
+        buffer.write_u16(1)?;
+        buffer.write_u32(ttl)?;
+        buffer.write_rdata(|buffer| buffer.write_qname(host))?;
+      }
+      DnsRecord::SOA {
+        ref domain,
+        ref m_name,
+        ref r_name,
+        serial,
+        refresh,
+        retry,
+        expire,
+        minimum,
+        ttl,
+      } => {
+        buffer.write_qname(domain)?;
+        buffer.write_u16(QueryType::SOA.to_num())?;
+        buffer.write_u16(1)?;
+        buffer.write_u32(ttl)?;
+        buffer.write_rdata(|buffer| {
+          buffer.write_qname(m_name)?;
+          buffer.write_qname(r_name)?;
+          buffer.write_u32(serial)?;
+          buffer.write_u32(refresh)?;
+          buffer.write_u32(retry)?;
+          buffer.write_u32(expire)?;
+          buffer.write_u32(minimum)
+        })?;
+      }
+      DnsRecord::PTR {
+        ref domain,
+        ref host,
+        ttl,
+      } => {
+        buffer.write_qname(domain)?;
+        buffer.write_u16(QueryType::PTR.to_num())?;
+        buffer.write_u16(1)?;
+        buffer.write_u32(ttl)?;
+        buffer.write_rdata(|buffer| buffer.write_qname(host))?;
+      }
+      DnsRecord::MX {
+        ref domain,
+        priority,
+        ref host,
+        ttl,
+      } => {
+        buffer.write_qname(domain)?;
+        buffer.write_u16(QueryType::MX.to_num())?;
+        buffer.write_u16(1)?;
+        buffer.write_u32(ttl)?;
+        buffer.write_rdata(|buffer| {
+          buffer.write_u16(priority)?;
+          buffer.write_qname(host)
+        })?;
+      }
+      DnsRecord::SRV {
+        ref domain,
+        priority,
+        weight,
+        port,
+        ref host,
+        ttl,
+      } => {
+        buffer.write_qname(domain)?;
+        buffer.write_u16(QueryType::SRV.to_num())?;
+        buffer.write_u16(1)?;
+        buffer.write_u32(ttl)?;
+        // RFC 2782 says the target must not be compressed
+        buffer.write_rdata(|buffer| {
+          buffer.write_u16(priority)?;
+          buffer.write_u16(weight)?;
+          buffer.write_u16(port)?;
+          buffer.write_qname_uncompressed(host)
+        })?;
+      }
+      DnsRecord::AAAA {
+        ref domain,
+        ref addr,
+        ttl,
+      } => {
+        buffer.write_qname(domain)?;
+        buffer.write_u16(QueryType::AAAA.to_num())?;
+        buffer.write_u16(1)?;
+        buffer.write_u32(ttl)?;
+        buffer.write_u16(16)?;
+
+        for segment in addr.segments() {
+          buffer.write_u16(segment)?;
+        }
+      }
+      DnsRecord::TXT {
+        ref domain,
+        ref data,
+        ttl,
+      } => {
+        buffer.write_qname(domain)?;
+        buffer.write_u16(QueryType::TXT.to_num())?;
+        buffer.write_u16(1)?;
+        buffer.write_u32(ttl)?;
+        buffer.write_rdata(|buffer| {
+          for string in data {
+            if string.len() > 0xFF {
+              return Err("TXT character-string exceeds 255 bytes".into());
+            }
+            buffer.write_u8(string.len() as u8)?;
+            buffer.write_bytes(string)?;
+          }
+          Ok(())
+        })?;
+      }
+      DnsRecord::CAA {
+        ref domain,
+        flags,
+        ref tag,
+        ref value,
+        ttl,
+      } => {
+        buffer.write_qname(domain)?;
+        buffer.write_u16(QueryType::CAA.to_num())?;
+        buffer.write_u16(1)?;
+        buffer.write_u32(ttl)?;
+        buffer.write_rdata(|buffer| {
+          buffer.write_u8(flags)?;
+          if tag.is_empty() || tag.len() > 0xFF {
+            return Err("CAA tag must be 1 to 255 bytes".into());
+          }
+          buffer.write_u8(tag.len() as u8)?;
+          buffer.write_bytes(tag.as_bytes())?;
+          buffer.write_bytes(value)
+        })?;
+      }
+      DnsRecord::UNKNOWN {
+        ref domain,
+        qtype,
+        class,
+        ref data,
+        ttl,
+      } => {
+        buffer.write_qname(domain)?;
+        buffer.write_u16(qtype)?;
+        buffer.write_u16(class)?;
+        buffer.write_u32(ttl)?;
+        buffer.write_rdata(|buffer| buffer.write_bytes(data))?;
       }
     }
+
+    Ok(buffer.pos() - start_pos)
   }
 }
 
+/// The RDATA of a record outside class IN, as bytes we can write back out in any
+/// packet. The names in the RFC 1035 types may be compressed, and those pointers
+/// only mean something in the packet they came from, so they're spelled out.
+fn foreign_rdata(buffer: &mut PacketBuf, qtype: QueryType, len: u16) -> Result<Vec<u8>, DnsError> {
+  let start = buffer.pos();
+  let mut out = PacketBuf::with_limit(u16::MAX as usize);
+  let copy_name = |buffer: &mut PacketBuf, out: &mut PacketBuf| -> Result<(), DnsError> {
+    let mut name = String::new();
+    buffer.read_qname(&mut name)?;
+    out.write_qname_uncompressed(&name)
+  };
+  match qtype {
+    QueryType::NS | QueryType::CNAME | QueryType::PTR => copy_name(buffer, &mut out)?,
+    QueryType::MX => {
+      out.write_u16(buffer.read_u16()?)?;
+      copy_name(buffer, &mut out)?;
+    }
+    QueryType::SOA => {
+      copy_name(buffer, &mut out)?;
+      copy_name(buffer, &mut out)?;
+    }
+    _ => {}
+  }
+
+  // whatever's left is copied over as it is
+  let rest = (len as usize)
+    .checked_sub(buffer.pos() - start)
+    .ok_or_else(|| DnsError::from(format!("{} record data overran its length", qtype).as_str()))?;
+  out.write_bytes(buffer.get_range(buffer.pos(), rest)?)?;
+  buffer.step(rest)?;
+
+  Ok(out.buf)
+}
+
+/// Zone-file style presentation, e.g. `google.com. 300 IN A 142.250.72.14`.
+///
+/// Types we don't understand get the RFC 3597 treatment, `TYPE65 \# 3 abcdef`,
+/// so nothing is lost in the rendering.
+impl fmt::Display for DnsRecord {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DnsRecord::A { domain, addr, ttl } => write!(f, "{}. {} IN A {}", domain, ttl, addr),
+      DnsRecord::NS { domain, host, ttl } => write!(f, "{}. {} IN NS {}.", domain, ttl, host),
+      DnsRecord::CNAME { domain, host, ttl } => {
+        write!(f, "{}. {} IN CNAME {}.", domain, ttl, host)
+      }
+      DnsRecord::SOA {
+        domain,
+        m_name,
+        r_name,
+        serial,
+        refresh,
+        retry,
+        expire,
+        minimum,
+        ttl,
+      } => write!(
+        f,
+        "{}. {} IN SOA {}. {}. {} {} {} {} {}",
+        domain, ttl, m_name, r_name, serial, refresh, retry, expire, minimum
+      ),
+      DnsRecord::PTR { domain, host, ttl } => write!(f, "{}. {} IN PTR {}.", domain, ttl, host),
+      DnsRecord::MX {
+        domain,
+        priority,
+        host,
+        ttl,
+      } => write!(f, "{}. {} IN MX {} {}.", domain, ttl, priority, host),
+      DnsRecord::TXT { domain, data, ttl } => {
+        write!(f, "{}. {} IN TXT", domain, ttl)?;
+        for string in data {
+          write!(f, " \"{}\"", escape_character_string(string))?;
+        }
+        Ok(())
+      }
+      DnsRecord::AAAA { domain, addr, ttl } => write!(f, "{}. {} IN AAAA {}", domain, ttl, addr),
+      DnsRecord::SRV {
+        domain,
+        priority,
+        weight,
+        port,
+        host,
+        ttl,
+      } => write!(
+        f,
+        "{}. {} IN SRV {} {} {} {}.",
+        domain, ttl, priority, weight, port, host
+      ),
+      DnsRecord::CAA {
+        domain,
+        flags,
+        tag,
+        value,
+        ttl,
+      } => write!(
+        f,
+        "{}. {} IN CAA {} {} \"{}\"",
+        domain,
+        ttl,
+        flags,
+        tag,
+        escape_character_string(value)
+      ),
+      DnsRecord::UNKNOWN {
+        domain,
+        qtype,
+        class,
+        data,
+        ttl,
+      } => {
+        write!(f, "{}. {} ", domain, ttl)?;
+        if *class == 1 {
+          write!(f, "IN")?;
+        } else {
+          write!(f, "CLASS{}", class)?;
+        }
+        write!(f, " TYPE{} \\# {}", qtype, data.len())?;
+        if !data.is_empty() {
+          write!(f, " ")?;
+          for b in data {
+            write!(f, "{:02x}", b)?;
+          }
+        }
+        Ok(())
+      }
+    }
+  }
+}
+
+/// Quote-safe rendering of a character-string: printable ascii as-is, `"` and `\`
+/// backslashed, and everything else as `\DDD`.
+fn escape_character_string(bytes: &[u8]) -> String {
+  let mut out = String::new();
+  for b in bytes {
+    match b {
+      b'"' | b'\\' => {
+        out.push('\\');
+        out.push(*b as char);
+      }
+      0x20..=0x7e => out.push(*b as char),
+      _ => out.push_str(&format!("\\{:03}", b)),
+    }
+  }
+  out
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum ResultCode {
-  NOERROR = 0,
-  FORMERR = 1,
-  SERVFAIL = 2,
-  NXDOMAIN = 3,
-  NOTIMP = 4,
-  REFUSED = 5,
+  NOERROR,  // 0
+  FORMERR,  // 1
+  SERVFAIL, // 2
+  NXDOMAIN, // 3
+  NOTIMP,   // 4
+  REFUSED,  // 5
+  YXDOMAIN, // 6
+  YXRRSET,  // 7
+  NXRRSET,  // 8
+  NOTAUTH,  // 9
+  NOTZONE,  // 10
+  // 11-15 aren't assigned, but whatever an upstream sent is passed on as it is
+  UNKNOWN(u8),
 }
 
 impl ResultCode {
+  pub fn to_num(self) -> u8 {
+    match self {
+      ResultCode::NOERROR => 0,
+      ResultCode::FORMERR => 1,
+      ResultCode::SERVFAIL => 2,
+      ResultCode::NXDOMAIN => 3,
+      ResultCode::NOTIMP => 4,
+      ResultCode::REFUSED => 5,
+      ResultCode::YXDOMAIN => 6,
+      ResultCode::YXRRSET => 7,
+      ResultCode::NXRRSET => 8,
+      ResultCode::NOTAUTH => 9,
+      ResultCode::NOTZONE => 10,
+      ResultCode::UNKNOWN(x) => x,
+    }
+  }
+
   pub fn from_num(num: u8) -> ResultCode {
     match num {
+      0 => ResultCode::NOERROR,
       1 => ResultCode::FORMERR,
       2 => ResultCode::SERVFAIL,
       3 => ResultCode::NXDOMAIN,
       4 => ResultCode::NOTIMP,
       5 => ResultCode::REFUSED,
-      0 | _ => ResultCode::NOERROR,
+      6 => ResultCode::YXDOMAIN,
+      7 => ResultCode::YXRRSET,
+      8 => ResultCode::NXRRSET,
+      9 => ResultCode::NOTAUTH,
+      10 => ResultCode::NOTZONE,
+      _ => ResultCode::UNKNOWN(num),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct DnsQuestion {
+  pub(crate) name: String,
+  pub(crate) qtype: QueryType,
+  pub(crate) class: u16,
+}
+
+impl DnsQuestion {
+  pub fn read(buffer: &mut PacketBuf) -> Result<DnsQuestion, DnsError> {
+    let truncated = |_| {
+      DnsError::Regular(ErrorKind::ParseError {
+        field: "question".to_string(),
+      })
+    };
+    let mut name = String::new();
+    buffer.read_qname(&mut name)?;
+    let qtype = QueryType::from_num(buffer.read_u16().map_err(truncated)?);
+    let class = buffer.read_u16().map_err(truncated)?;
+
+    Ok(DnsQuestion { name, qtype, class })
+  }
+
+  pub fn write(&self, buffer: &mut PacketBuf) -> Result<(), DnsError> {
+    buffer.write_qname(&self.name)?;
+    buffer.write_u16(self.qtype.to_num())?;
+    buffer.write_u16(self.class)?;
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+impl DnsQuestion {
+  /// A class IN question, which is all the tests ever need
+  pub(crate) fn new(name: &str, qtype: QueryType) -> DnsQuestion {
+    DnsQuestion {
+      name: name.to_string(),
+      qtype,
+      class: 1,
+    }
+  }
+}
+
+/// Put a name the way we read them off the wire: lowercase, with no trailing dot,
+/// and the root as an empty string.
+pub(crate) fn normalize_name(name: &str) -> String {
+  name.trim().trim_end_matches('.').to_lowercase()
+}
+
+/// Whether `name` is `zone` or somewhere underneath it. Both should already be
+/// normalized. Matching is on whole labels, so `notexample.com` isn't under
+/// `example.com`.
+pub(crate) fn in_zone(name: &str, zone: &str) -> bool {
+  zone.is_empty()
+    || name == zone
+    || (name.len() > zone.len()
+      && name.ends_with(zone)
+      && name.as_bytes()[name.len() - zone.len() - 1] == b'.')
+}
+
+/*
+    An OPT pseudo-record (RFC 6891) reuses the fixed record fields for its own ends:
+
+        Name:  <Root>
+        Type:  OPT (41)
+        Class: UDP payload size, e.g. 4096
+        TTL:   EXTENDED-RCODE (8 bits) | VERSION (8 bits) | DO (1 bit) | Z (15 bits)
+        RDATA: { OPTION-CODE u16, OPTION-LENGTH u16, OPTION-DATA } ...
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct EdnsOption {
+  pub(crate) code: u16,
+  pub(crate) data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Edns {
+  pub(crate) payload_size: u16,
+  pub(crate) extended_rcode: u8,
+  pub(crate) version: u8,
+  pub(crate) dnssec_ok: bool,
+  pub(crate) options: Vec<EdnsOption>,
+}
+
+impl Default for Edns {
+  fn default() -> Edns {
+    Edns {
+      payload_size: EDNS_PAYLOAD_SIZE,
+      extended_rcode: 0,
+      version: 0,
+      dnssec_ok: false,
+      options: Vec::new(),
     }
   }
 }
 
-#[derive(Debug, Default)]
+impl Edns {
+  /// Unpack an OPT record that DnsRecord::read handed us as raw data
+  pub fn from_record(record: &DnsRecord) -> Result<Option<Edns>, DnsError> {
+    let (domain, class, data, ttl) = match record {
+      DnsRecord::UNKNOWN {
+        domain,
+        qtype,
+        class,
+        data,
+        ttl,
+      } if *qtype == QueryType::OPT.to_num() => (domain, *class, data, *ttl),
+      _ => return Ok(None),
+    };
+    if !domain.is_empty() {
+      return Err("OPT record not owned by the root".into());
+    }
+
+    let mut buffer = PacketBuf::from_bytes(data)?;
+    let mut options = Vec::new();
+    while buffer.pos() < data.len() {
+      let code = buffer.read_u16()?;
+      let len = buffer.read_u16()? as usize;
+      let data = buffer.get_range(buffer.pos(), len)?.to_vec();
+      buffer.step(len)?;
+      options.push(EdnsOption { code, data });
+    }
+
+    Ok(Some(Edns {
+      payload_size: class,
+      extended_rcode: ((ttl >> 24) & 0xFF) as u8,
+      version: ((ttl >> 16) & 0xFF) as u8,
+      dnssec_ok: (ttl & 0b1000_0000_0000_0000) != 0,
+      options,
+    }))
+  }
+
+  pub fn write(&self, buffer: &mut PacketBuf) -> Result<usize, DnsError> {
+    let start_pos = buffer.pos();
+
+    buffer.write_qname("")?;
+    buffer.write_u16(QueryType::OPT.to_num())?;
+    buffer.write_u16(self.payload_size)?;
+    let mut ttl = ((self.extended_rcode as u32) << 24) | ((self.version as u32) << 16);
+    if self.dnssec_ok {
+      ttl |= 0b1000_0000_0000_0000;
+    }
+    buffer.write_u32(ttl)?;
+    buffer.write_rdata(|buffer| {
+      for option in &self.options {
+        buffer.write_u16(option.code)?;
+        let len = match u16::try_from(option.data.len()) {
+          Ok(len) => len,
+          Err(_) => return Err("EDNS option data exceeds 65535 bytes".into()),
+        };
+        buffer.write_u16(len)?;
+        buffer.write_bytes(&option.data)?;
+      }
+      Ok(())
+    })?;
+
+    Ok(buffer.pos() - start_pos)
+  }
+
+  /// The biggest udp response the sender of this OPT record will take. Anything
+  /// under 512 is treated as 512 (RFC 6891 6.2.5), and we cap it at what we
+  /// advertise ourselves.
+  pub fn max_payload(&self) -> usize {
+    (self.payload_size as usize).clamp(UDP_PAYLOAD_SIZE, EDNS_PAYLOAD_SIZE as usize)
+  }
+}
+
+#[derive(Debug, Default, Clone)]
 pub(crate) struct DnsMessage {
   pub(crate) tx_id: u16,
-  pub(crate) raw_flags: u16,
-  pub(crate) questions: u16,
-  pub(crate) answers: u16,
-  pub(crate) answer_rrs: u16,
-  pub(crate) authority_rrs: u16,
-  pub(crate) additional_rrs: u16,
-  pub(crate) host: String,
-  flags: Flags,
+  pub(crate) flags: Flags,
+  pub(crate) questions: Vec<DnsQuestion>,
+  pub(crate) answers: Vec<DnsRecord>,
+  pub(crate) authorities: Vec<DnsRecord>,
+  pub(crate) additionals: Vec<DnsRecord>,
+  // pulled out of the additional section when there's an OPT record in it
+  pub(crate) edns: Option<Edns>,
 }
 /*
     Flags: 0x0120 Standard query
@@ -193,21 +1014,7 @@ pub(crate) struct DnsMessage {
         .... .... ...0 .... = Non-authenticated data: Unacceptable
 
 */
-#[derive(Debug)]
-pub(crate) enum DnsResponseErrorType {
-  NoError = 0,
-  NXRecord = 1,
-  ServerFailure = 2,
-  FormatError = 4,
-}
-
-impl fmt::Display for DnsResponseErrorType {
-  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    write!(f, "DnsResponseErrorType: {:?}", self)
-  }
-}
-
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum DnsMessageType {
   Query = 0,
   Response,
@@ -233,62 +1040,144 @@ impl TryFrom<u16> for DnsMessageType {
 }
 */
 
-#[derive(Debug)]
-pub(crate) enum MessageType {
-  Standard = 0,
-  Inverse = 4, // in-addr.arpa
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) enum Opcode {
+  QUERY,  // 0
+  IQUERY, // 1, obsolete in-addr.arpa style inverse query
+  STATUS, // 2
+  NOTIFY, // 4
+  UPDATE, // 5
+  UNKNOWN(u8),
 }
 
-impl fmt::Display for MessageType {
+impl Opcode {
+  pub fn to_num(self) -> u8 {
+    match self {
+      Opcode::QUERY => 0,
+      Opcode::IQUERY => 1,
+      Opcode::STATUS => 2,
+      Opcode::NOTIFY => 4,
+      Opcode::UPDATE => 5,
+      Opcode::UNKNOWN(x) => x,
+    }
+  }
+
+  pub fn from_num(num: u8) -> Opcode {
+    match num {
+      0 => Opcode::QUERY,
+      1 => Opcode::IQUERY,
+      2 => Opcode::STATUS,
+      4 => Opcode::NOTIFY,
+      5 => Opcode::UPDATE,
+      _ => Opcode::UNKNOWN(num),
+    }
+  }
+}
+
+impl fmt::Display for Opcode {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    write!(f, "QueryType: {:?}", self)
+    write!(f, "Opcode: {:?}", self)
   }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct Flags {
-  rq: DnsMessageType,
-  query_type: MessageType,
-  authoritative: bool,
-  truncated: bool,
-  recursive: bool,
-  recursion_available: bool,
-  authenticated: bool,
-  error: DnsResponseErrorType,
+  pub(crate) rq: DnsMessageType,
+  pub(crate) opcode: Opcode,
+  pub(crate) authoritative: bool,
+  pub(crate) truncated: bool,
+  pub(crate) recursive: bool,
+  pub(crate) recursion_available: bool,
+  pub(crate) z: bool,
+  pub(crate) authenticated: bool,
+  pub(crate) checking_disabled: bool,
+  pub(crate) rcode: ResultCode,
 }
 
 impl Default for Flags {
   fn default() -> Flags {
     Flags {
       rq: DnsMessageType::Query,
-      query_type: MessageType::Standard,
+      opcode: Opcode::QUERY,
       authoritative: false,
       truncated: false,
       recursive: false,
       recursion_available: false,
+      z: false,
       authenticated: false,
-      error: DnsResponseErrorType::NoError,
+      checking_disabled: false,
+      rcode: ResultCode::NOERROR,
     }
   }
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum QueryType {
   UNKNOWN(u16),
-  A, // 1
+  A,     // 1
+  NS,    // 2
+  CNAME, // 5
+  SOA,   // 6
+  PTR,   // 12
+  MX,    // 15
+  TXT,   // 16
+  AAAA,  // 28
+  SRV,   // 33
+  OPT,   // 41
+  ANY,   // 255, only ever asked for
+  CAA,   // 257
+}
+
+impl fmt::Display for QueryType {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      QueryType::UNKNOWN(x) => write!(f, "TYPE{}", x),
+      _ => write!(f, "{:?}", self),
+    }
+  }
+}
+
+impl Default for QueryType {
+  fn default() -> QueryType {
+    QueryType::UNKNOWN(0)
+  }
 }
 
 impl QueryType {
-  pub fn to_num(&self) -> u16 {
-    match *self {
+  pub fn to_num(self) -> u16 {
+    match self {
       QueryType::UNKNOWN(x) => x,
       QueryType::A => 1,
+      QueryType::NS => 2,
+      QueryType::CNAME => 5,
+      QueryType::SOA => 6,
+      QueryType::PTR => 12,
+      QueryType::MX => 15,
+      QueryType::TXT => 16,
+      QueryType::AAAA => 28,
+      QueryType::SRV => 33,
+      QueryType::OPT => 41,
+      QueryType::ANY => 255,
+      QueryType::CAA => 257,
     }
   }
 
   pub fn from_num(num: u16) -> QueryType {
     match num {
       1 => QueryType::A,
+      2 => QueryType::NS,
+      5 => QueryType::CNAME,
+      6 => QueryType::SOA,
+      12 => QueryType::PTR,
+      15 => QueryType::MX,
+      16 => QueryType::TXT,
+      28 => QueryType::AAAA,
+      33 => QueryType::SRV,
+      41 => QueryType::OPT,
+      255 => QueryType::ANY,
+      257 => QueryType::CAA,
       _ => QueryType::UNKNOWN(num),
     }
   }
@@ -296,18 +1185,38 @@ impl QueryType {
 
 #[derive(Debug)]
 pub(crate) struct PacketBuf {
-  pub buf: [u8; 512],
+  pub buf: Vec<u8>,
   pub pos: usize,
+  // the most we're allowed to write; 512 for plain udp, more if the client told us
+  // (via edns) that it can take it
+  limit: usize,
+  // lowercased name suffixes we've already written, and the offset they start at,
+  // so later names can point back at them instead of spelling them out again
+  labels: HashMap<String, usize>,
 }
 
 impl PacketBuf {
   /// This gives us a fresh buffer for holding the packet contents, and a
-  /// field for keeping track of where we are.
-  pub fn new() -> PacketBuf {
+  /// field for keeping track of where we are. It will refuse to grow past
+  /// `limit` bytes.
+  pub fn with_limit(limit: usize) -> PacketBuf {
     PacketBuf {
-      buf: [0; 512],
+      buf: Vec::with_capacity(limit),
       pos: 0,
+      limit,
+      labels: HashMap::new(),
+    }
+  }
+
+  /// Load a received packet into a fresh buffer so we can read it back out.
+  pub fn from_bytes(bytes: &[u8]) -> Result<PacketBuf, DnsError> {
+    if bytes.len() > u16::MAX as usize {
+      return Err("packet too large for buffer".into());
     }
+    let mut buffer = PacketBuf::with_limit(bytes.len());
+    buffer.buf.extend_from_slice(bytes);
+
+    Ok(buffer)
   }
 
   /// Current position within buffer
@@ -316,8 +1225,13 @@ impl PacketBuf {
   }
 
   /// Step the buffer position forward a specific number of steps
-  fn step(&mut self, steps: usize) {
+  fn step(&mut self, steps: usize) -> Result<(), DnsError> {
+    if self.pos + steps > self.buf.len() {
+      return Err("End of buffer".into());
+    }
     self.pos += steps;
+
+    Ok(())
   }
 
   /// Change the buffer position
@@ -327,7 +1241,7 @@ impl PacketBuf {
 
   /// Read a single byte and move the position one step forward
   fn read(&mut self) -> Result<u8, DnsError> {
-    if self.pos >= 512 {
+    if self.pos >= self.buf.len() {
       return Err("End of buffer".into());
     }
     let res = self.buf[self.pos];
@@ -338,7 +1252,7 @@ impl PacketBuf {
 
   /// Get a single byte, without changing the buffer position
   fn get(&mut self, pos: usize) -> Result<u8, DnsError> {
-    if pos >= 512 {
+    if pos >= self.buf.len() {
       return Err("End of buffer".into());
     }
     Ok(self.buf[pos])
@@ -346,7 +1260,7 @@ impl PacketBuf {
 
   /// Get a range of bytes
   fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8], DnsError> {
-    if start + len >= 512 {
+    if start + len > self.buf.len() {
       return Err("End of buffer".into());
     }
     Ok(&self.buf[start..start + len])
@@ -364,7 +1278,7 @@ impl PacketBuf {
     let res = ((self.read()? as u32) << 24)
       | ((self.read()? as u32) << 16)
       | ((self.read()? as u32) << 8)
-      | ((self.read()? as u32) << 0);
+      | (self.read()? as u32);
 
     Ok(res)
   }
@@ -430,6 +1344,15 @@ impl PacketBuf {
 
         continue;
       }
+      // 0x40 and 0x80 are the extended and reserved label types; nobody sends
+      // these, and guessing at them would just read garbage
+      else if (len & 0xC0) != 0 {
+        return Err(
+          format!("Unsupported label type {:#04x}", len)
+            .as_str()
+            .into(),
+        );
+      }
       // The base scenario, where we're reading a single label and
       // appending it to the output:
       else {
@@ -454,6 +1377,12 @@ impl PacketBuf {
 
         // Move forward the full length of the label.
         pos += len as usize;
+
+        // 255 octets is the most a name can be on the wire; anything longer
+        // is a crafted packet
+        if outstr.len() > 255 {
+          return Err("Name exceeds 255 characters of length".into());
+        }
       }
     }
 
@@ -466,6 +1395,136 @@ impl PacketBuf {
 
     Ok(())
   }
+
+  /// Write a single byte and move the position one step forward
+  fn write(&mut self, val: u8) -> Result<(), DnsError> {
+    if self.pos >= self.limit {
+      return Err(DnsError::Regular(ErrorKind::BufferFull));
+    }
+    if self.pos == self.buf.len() {
+      self.buf.push(val);
+    } else {
+      self.buf[self.pos] = val;
+    }
+    self.pos += 1;
+
+    Ok(())
+  }
+
+  /// Write a single byte
+  fn write_u8(&mut self, val: u8) -> Result<(), DnsError> {
+    self.write(val)
+  }
+
+  /// Write two bytes in network order, stepping two steps forward
+  fn write_u16(&mut self, val: u16) -> Result<(), DnsError> {
+    self.write((val >> 8) as u8)?;
+    self.write((val & 0xFF) as u8)?;
+
+    Ok(())
+  }
+
+  /// Write four bytes in network order, stepping four steps forward
+  fn write_u32(&mut self, val: u32) -> Result<(), DnsError> {
+    self.write(((val >> 24) & 0xFF) as u8)?;
+    self.write(((val >> 16) & 0xFF) as u8)?;
+    self.write(((val >> 8) & 0xFF) as u8)?;
+    self.write((val & 0xFF) as u8)?;
+
+    Ok(())
+  }
+
+  /// Write a qname
+  ///
+  /// The inverse of `read_qname`: takes www.google.com and writes
+  /// [3]www[6]google[3]com[0]. If we've already written a name ending in
+  /// google.com, we write [3]www and then a two byte pointer (0xC0 | offset)
+  /// back to it instead.
+  fn write_qname(&mut self, qname: &str) -> Result<(), DnsError> {
+    self.write_labels(qname, true)
+  }
+
+  /// Write a qname in full, for the places (like SRV targets) where pointers
+  /// aren't allowed
+  fn write_qname_uncompressed(&mut self, qname: &str) -> Result<(), DnsError> {
+    self.write_labels(qname, false)
+  }
+
+  fn write_labels(&mut self, qname: &str, compress: bool) -> Result<(), DnsError> {
+    let labels: Vec<&str> = qname.split('.').filter(|l| !l.is_empty()).collect();
+    for (i, label) in labels.iter().enumerate() {
+      let len = label.len();
+      if len > 0x3f {
+        return Err("Single label exceeds 63 characters of length".into());
+      }
+
+      let suffix = labels[i..].join(".").to_lowercase();
+      if let Some(&offset) = self.labels.get(&suffix).filter(|_| compress) {
+        self.write_u16(0xC000 | offset as u16)?;
+        return Ok(());
+      }
+      // pointers only have 14 bits to work with, so anything past that can't be
+      // pointed at
+      if self.pos < 0x4000 {
+        self.labels.insert(suffix, self.pos);
+      }
+
+      self.write_u8(len as u8)?;
+      for b in label.as_bytes() {
+        self.write_u8(*b)?;
+      }
+    }
+
+    self.write_u8(0)?;
+
+    Ok(())
+  }
+
+  /// Write a run of raw bytes
+  fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), DnsError> {
+    for b in bytes {
+      self.write_u8(*b)?;
+    }
+
+    Ok(())
+  }
+
+  /// Write a record's RDATA, prefixed with its length
+  ///
+  /// Anything with a name in it can't know its length until the name has been
+  /// written (and maybe compressed), so we leave room for the length, let `f`
+  /// write the data, then go back and fill it in.
+  fn write_rdata<F>(&mut self, f: F) -> Result<(), DnsError>
+  where
+    F: FnOnce(&mut PacketBuf) -> Result<(), DnsError>,
+  {
+    let len_pos = self.pos();
+    self.write_u16(0)?;
+    f(self)?;
+    let size = self.pos() - (len_pos + 2);
+    self.set_u16(len_pos, size as u16)?;
+
+    Ok(())
+  }
+
+  /// Overwrite a single byte at a position we've already written, without moving
+  fn set(&mut self, pos: usize, val: u8) -> Result<(), DnsError> {
+    if pos >= self.buf.len() {
+      return Err("End of buffer".into());
+    }
+    self.buf[pos] = val;
+
+    Ok(())
+  }
+
+  /// Overwrite two bytes at a position we've already written; handy for patching
+  /// counts and lengths once we know them
+  fn set_u16(&mut self, pos: usize, val: u16) -> Result<(), DnsError> {
+    self.set(pos, (val >> 8) as u8)?;
+    self.set(pos + 1, (val & 0xFF) as u8)?;
+
+    Ok(())
+  }
 }
 /*
     0... .... .... .... = Response: Message is a query
@@ -481,26 +1540,56 @@ impl PacketBuf {
     .010 0 would be dec 4, and would be an inverse request
 */
 impl Flags {
-  fn new(raw_flags: u16) -> Self {
+  pub(crate) fn new(raw_flags: u16) -> Self {
     Flags {
       rq: if (raw_flags & 0b1000_0000_0000_0000) == 0 {
         DnsMessageType::Query
       } else {
         DnsMessageType::Response
       },
-      query_type: if (raw_flags & 0b0100_0000_0000_0000) == 0 {
-        MessageType::Standard
-      } else {
-        MessageType::Inverse
-      },
-      authoritative: true,
-      truncated: false,
-      recursive: true,
-      recursion_available: true,
-      authenticated: false,
-      error: DnsResponseErrorType::NoError,
+      opcode: Opcode::from_num(((raw_flags >> 11) & 0b1111) as u8),
+      authoritative: (raw_flags & 0b0000_0100_0000_0000) != 0,
+      truncated: (raw_flags & 0b0000_0010_0000_0000) != 0,
+      recursive: (raw_flags & 0b0000_0001_0000_0000) != 0,
+      recursion_available: (raw_flags & 0b0000_0000_1000_0000) != 0,
+      z: (raw_flags & 0b0000_0000_0100_0000) != 0,
+      authenticated: (raw_flags & 0b0000_0000_0010_0000) != 0,
+      checking_disabled: (raw_flags & 0b0000_0000_0001_0000) != 0,
+      rcode: ResultCode::from_num((raw_flags & 0b1111) as u8),
     }
   }
+
+  /// The inverse of `new`: pack everything back into the two header bytes
+  pub(crate) fn to_u16(&self) -> u16 {
+    let mut raw_flags: u16 = 0;
+    if self.rq == DnsMessageType::Response {
+      raw_flags |= 0b1000_0000_0000_0000;
+    }
+    raw_flags |= ((self.opcode.to_num() & 0b1111) as u16) << 11;
+    if self.authoritative {
+      raw_flags |= 0b0000_0100_0000_0000;
+    }
+    if self.truncated {
+      raw_flags |= 0b0000_0010_0000_0000;
+    }
+    if self.recursive {
+      raw_flags |= 0b0000_0001_0000_0000;
+    }
+    if self.recursion_available {
+      raw_flags |= 0b0000_0000_1000_0000;
+    }
+    if self.z {
+      raw_flags |= 0b0000_0000_0100_0000;
+    }
+    if self.authenticated {
+      raw_flags |= 0b0000_0000_0010_0000;
+    }
+    if self.checking_disabled {
+      raw_flags |= 0b0000_0000_0001_0000;
+    }
+    raw_flags |= (self.rcode.to_num() & 0b1111) as u16;
+    raw_flags
+  }
 }
 
 impl fmt::Display for Flags {
@@ -514,16 +1603,20 @@ impl fmt::Display for Flags {
             truncated: {} 
             recursive: {} 
             recursion_available: {} 
+            z: {} 
             authenticated: {} 
-            {}"#,
+            checking_disabled: {} 
+            rcode: {:?}"#,
       self.rq,
-      self.query_type,
+      self.opcode,
       self.authoritative,
       self.truncated,
       self.recursive,
       self.recursion_available,
+      self.z,
       self.authenticated,
-      self.error
+      self.checking_disabled,
+      self.rcode
     )
   }
 }
@@ -534,26 +1627,31 @@ impl fmt::Display for DnsMessage {
       f,
       r#"DnsMessage tx_id: == {:02x} ==
       flags: {} 
-      questions: {:02x}, answer_rrs: {:02x}
-      host: {:?}
+      questions: {:?}
+      answers: {:?}
+      authorities: {:?}
+      additionals: {:?}
       "#,
-      self.tx_id, self.flags, self.questions, self.answer_rrs, self.host
+      self.tx_id, self.flags, self.questions, self.answers, self.authorities, self.additionals
     )
   }
 }
 
 impl DnsMessage {
   pub(crate) fn parse(&mut self, buf: &[u8]) -> Result<&mut DnsMessage, DnsError> {
-    self.tx_id = u16::from_be_bytes(buf[0..2].try_into()?);
-    self.raw_flags = u16::from_be_bytes(buf[2..4].try_into()?);
+    if buf.len() < usize::from(HEADER_LEN) {
+      return Err("datagram shorter than a dns header".into());
+    }
+    let mut buffer = PacketBuf::from_bytes(buf)?;
+    self.tx_id = buffer.read_u16()?;
+    self.flags = Flags::new(buffer.read_u16()?);
     // multiple questions basically not supported by any dns server
     // https://stackoverflow.com/questions/4082081/requesting-a-and-aaaa-records-in-single-dns-query/4083071#4083071
-    // self.questions = 1;
-    self.questions = u16::from_be_bytes(buf[4..6].try_into()?);
-    self.answers = self.questions; // TODO FIXME
-    self.answer_rrs = u16::from_be_bytes(buf[6..8].try_into()?);
-    self.authority_rrs = u16::from_be_bytes(buf[8..10].try_into()?);
-    self.additional_rrs = u16::from_be_bytes(buf[10..12].try_into()?);
+    // but we keep however many we're told about
+    let questions = buffer.read_u16()?;
+    let answers = buffer.read_u16()?;
+    let authorities = buffer.read_u16()?;
+    let additionals = buffer.read_u16()?;
     // the header's 12 bytes long
     // now we're at query
     // first we're going to be told how long the first unicode string is,
@@ -568,99 +1666,692 @@ impl DnsMessage {
        DEC    6                    3           0       1      1
        thanks https://github.com/EmilHernvall/dnsguide/blob/master/chapter1.md for the chart
     */
-    self.flags = Flags::new(self.raw_flags);
-    self.host = self.remove_dot(self.read_qname(buf));
+    self.questions = (0..questions)
+      .map(|_| DnsQuestion::read(&mut buffer))
+      .collect::<Result<_, _>>()?;
+    self.answers = (0..answers)
+      .map(|_| DnsRecord::read(&mut buffer))
+      .collect::<Result<_, _>>()?;
+    self.authorities = (0..authorities)
+      .map(|_| DnsRecord::read(&mut buffer))
+      .collect::<Result<_, _>>()?;
+    self.additionals = (0..additionals)
+      .map(|_| DnsRecord::read(&mut buffer))
+      .collect::<Result<_, _>>()?;
+    let mut edns = None;
+    let mut additionals = Vec::new();
+    for record in self.additionals.drain(..) {
+      match Edns::from_record(&record)? {
+        Some(_) if edns.is_some() => return Err("more than one OPT record".into()),
+        Some(e) => edns = Some(e),
+        None => additionals.push(record),
+      }
+    }
+    self.additionals = additionals;
+    self.edns = edns;
     Ok(self)
   }
 
-  pub(crate) fn dns_message_as_byte_vec(&self) -> Result<Vec<u16>, DnsError> {
-    let mut r = Vec::new();
+  /// Serialize into at most `limit` bytes
+  pub(crate) fn to_wire(&self, limit: usize) -> Result<Vec<u8>, DnsError> {
+    let mut buffer = PacketBuf::with_limit(limit);
+    self.write(&mut buffer)?;
+    Ok(buffer.buf)
+  }
+
+  /// Serialize a response for a client, in at most `limit` bytes.
+  ///
+  /// If it won't fit over udp we fall back to the header, question and OPT record
+  /// with TC set, which tells the client to come back and ask over tcp. Over tcp
+  /// there's nowhere else to go, so a response that won't fit there, or one with a
+  /// record we can't encode at all, goes out as a SERVFAIL instead.
+  pub(crate) fn to_response(&self, limit: usize, udp: bool) -> Result<Vec<u8>, DnsError> {
+    let err = match self.to_wire(limit) {
+      Ok(bytes) => return Ok(bytes),
+      Err(e) => e,
+    };
+
+    let mut fallback = DnsMessage {
+      tx_id: self.tx_id,
+      flags: self.flags.clone(),
+      questions: self.questions.clone(),
+      edns: self.edns.clone(),
+      ..DnsMessage::default()
+    };
+    match err {
+      DnsError::Regular(ErrorKind::BufferFull) if udp => fallback.flags.truncated = true,
+      e => {
+        eprintln!("couldn't encode response, sending SERVFAIL: {}", e);
+        fallback.flags.truncated = false;
+        fallback.flags.authoritative = false;
+        fallback.flags.rcode = ResultCode::SERVFAIL;
+      }
+    }
+    fallback.to_wire(limit).or_else(|_| {
+      // the question itself can't be written, so the header is all they get
+      fallback.questions.clear();
+      fallback.to_wire(limit)
+    })
+  }
+
+  /// How big a udp response the sender of this message can take
+  pub(crate) fn max_udp_payload(&self) -> usize {
+    match &self.edns {
+      Some(edns) => edns.max_payload(),
+      None => UDP_PAYLOAD_SIZE,
+    }
+  }
+
+  /// Serialize the message into RFC 1035 wire format, ready to hand to `send_to`.
+  ///
+  /// The section counts in the header are written as zero and patched with `set_u16`
+  /// once each section is written. A record we can't encode fails the whole
+  /// message, and so does running out of room under the buffer's limit, which
+  /// comes back as `ErrorKind::BufferFull` so `to_response` can tell the two apart.
+  pub(crate) fn write(&self, buffer: &mut PacketBuf) -> Result<(), DnsError> {
+    let header_pos = buffer.pos();
     // keep the tx_id so we're part of the same dns 'conversation'
-    r.push(self.tx_id);
-    // then set our qr field to 1 (reply; 0 is query)
-    let mut our_bits = self.raw_flags;
-    our_bits |= 0b1000_0000_0000_0000;
-    eprintln!("original bits: {:016b}", self.raw_flags);
-    eprintln!("our bits:      {:016b}", our_bits);
-    r.push(our_bits);
-    r.push(self.questions);
-    r.push(self.answer_rrs);
-    r.push(self.authority_rrs);
-    r.push(self.additional_rrs);
-    for c in self.host.chars() {
-      let mut _b = [0; 1];
-      c.encode_utf16(&mut _b);
-      r.push(_b[0]);
-    }
-    Ok(r)
-  }
-
-  // we have to step through one name chunk at a time
-  //   g o o g l e   c o m
-  // 6             3       0
-  // ^ here
-  //               ^ then here
-  //                       ^ then here, and discover we're done
-  pub(crate) fn read_qname(&self, buf: &[u8]) -> String {
-    let mut index = usize::from(HEADER_LEN);
-    let mut host_chunk_len = buf[index];
-    // we have to step through one name chunk at a time
-    //   g o o g l e   c o m
-    // 6             3       0
-    // ^ here
-    //               ^ then here
-    //                       ^ then here, and discover we're done
-    let mut temp_host = String::new();
-    while host_chunk_len != 0 {
-      temp_host.push_str(
-        String::from_utf8(
-          self
-            .take_next(buf, &mut index, host_chunk_len.into())
-            .unwrap(),
-        )
-        .unwrap()
-        .as_str(),
-      );
-      host_chunk_len = buf[index];
-      temp_host.push('.');
+    buffer.write_u16(self.tx_id)?;
+    buffer.write_u16(self.flags.to_u16())?;
+    for _ in 0..4 {
+      buffer.write_u16(0)?;
     }
-    temp_host
+
+    for question in &self.questions {
+      question.write(buffer)?;
+    }
+    buffer.set_u16(header_pos + 4, self.questions.len() as u16)?;
+    for (i, section) in [&self.answers, &self.authorities, &self.additionals]
+      .iter()
+      .enumerate()
+    {
+      for record in section.iter() {
+        record.write(buffer)?;
+      }
+      let mut count = section.len();
+      // the OPT record always goes last
+      if i == 2 {
+        if let Some(edns) = &self.edns {
+          edns.write(buffer)?;
+          count += 1;
+        }
+      }
+      buffer.set_u16(header_pos + 6 + i * 2, count as u16)?;
+    }
+
+    Ok(())
   }
 
   pub(crate) fn generate_response(&mut self) -> Result<&DnsMessage, DnsError> {
     // should be, all we have to do is set the flag as a response instead of request
     // then give back our object to be serialized and sent over the network back to client
     self.set_rq_type(DnsMessageType::Response);
+    // opcode, rd and cd are echoed back from the query; everything else is ours to say
+    self.flags.authoritative = false;
+    self.flags.truncated = false;
+    self.flags.recursion_available = true;
+    self.flags.z = false;
+    self.flags.authenticated = false;
+    self.flags.rcode = if self.flags.opcode == Opcode::QUERY {
+      ResultCode::NOERROR
+    } else {
+      ResultCode::NOTIMP
+    };
+    // we only answer with edns if we were asked with it, and then only with version
+    // 0 and the DO bit echoed; options like COOKIE we don't speak are dropped
+    if let Some(query_edns) = self.edns.take() {
+      let mut edns = Edns {
+        dnssec_ok: query_edns.dnssec_ok,
+        ..Edns::default()
+      };
+      if query_edns.version > 0 {
+        // BADVERS is 16, which is all upper bits
+        edns.extended_rcode = 1;
+        self.flags.rcode = ResultCode::NOERROR;
+      }
+      self.edns = Some(edns);
+    }
     //eprintln!("{:?}", self);
     Ok(self)
   }
 
+  /// Fill in our response with what an upstream resolver told us. The header
+  /// stays ours (tx_id, opcode, RA and friends), but the rcode and records are theirs.
+  pub(crate) fn relay(&mut self, upstream: DnsMessage) -> &DnsMessage {
+    self.flags.rcode = upstream.flags.rcode;
+    self.flags.authenticated = upstream.flags.authenticated;
+    self.answers = upstream.answers;
+    self.authorities = upstream.authorities;
+    self.additionals = upstream.additionals;
+    self
+  }
+
   fn set_rq_type(&mut self, t: DnsMessageType) -> &DnsMessage {
     self.flags.rq = t;
     self
   }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn a(domain: &str, last: u8) -> DnsRecord {
+    DnsRecord::A {
+      domain: domain.to_string(),
+      addr: Ipv4Addr::new(10, 0, 0, last),
+      ttl: 300,
+    }
+  }
+
+  fn response(answers: Vec<DnsRecord>) -> DnsMessage {
+    let mut message = DnsMessage {
+      tx_id: 0xbeef,
+      questions: vec![DnsQuestion::new("www.example.test", QueryType::A)],
+      answers,
+      ..DnsMessage::default()
+    };
+    message.flags.rq = DnsMessageType::Response;
+    message.flags.recursive = true;
+    message
+  }
+
+  /// A query for www.example.test A, with an OPT record built from `ttl` (extended
+  /// rcode, version and DO bit) and carrying a COOKIE option
+  fn edns_query(payload_size: u16, ttl: u32) -> Vec<u8> {
+    let mut packet = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1];
+    packet.extend_from_slice(b"\x03www\x07example\x04test\x00\x00\x01\x00\x01");
+    packet.extend_from_slice(b"\x00\x00\x29");
+    packet.extend_from_slice(&payload_size.to_be_bytes());
+    packet.extend_from_slice(&ttl.to_be_bytes());
+    packet.extend_from_slice(b"\x00\x0c\x00\x0a\x00\x08cookie!!");
+    packet
+  }
+
+  fn round_trip(message: &DnsMessage, limit: usize) -> (Vec<u8>, DnsMessage) {
+    let wire = message.to_wire(limit).unwrap();
+    let mut parsed = DnsMessage::default();
+    parsed.parse(&wire).unwrap();
+    (wire, parsed)
+  }
+
+  #[test]
+  fn messages_are_written_in_wire_order() {
+    let wire = response(vec![a("host.invalid", 7)])
+      .to_wire(UDP_PAYLOAD_SIZE)
+      .unwrap();
+    let mut expected = vec![0xbe, 0xef, 0x81, 0x00, 0, 1, 0, 1, 0, 0, 0, 0];
+    expected.extend_from_slice(b"\x03www\x07example\x04test\x00\x00\x01\x00\x01");
+    expected.extend_from_slice(b"\x04host\x07invalid\x00\x00\x01\x00\x01\x00\x00\x01\x2c\x00\x04");
+    expected.extend_from_slice(&[10, 0, 0, 7]);
+    assert_eq!(wire, expected);
+  }
+
+  #[test]
+  fn aaaa_records_carry_all_sixteen_bytes() {
+    let mut message = response(vec![DnsRecord::AAAA {
+      domain: "www.example.test".into(),
+      addr: "2001:db8::53".parse().unwrap(),
+      ttl: 300,
+    }]);
+    message.questions[0].qtype = QueryType::AAAA;
+    let (wire, parsed) = round_trip(&message, UDP_PAYLOAD_SIZE);
+    let mut rdata = vec![0x00, 0x10, 0x20, 0x01, 0x0d, 0xb8];
+    rdata.extend_from_slice(&[0; 11]);
+    rdata.push(0x53);
+    assert!(wire.ends_with(&rdata));
+    assert_eq!(
+      &wire[wire.len() - rdata.len() - 8..][..4],
+      b"\x00\x1c\x00\x01"
+    );
+    assert_eq!(parsed.questions[0].qtype, QueryType::AAAA);
+    assert_eq!(parsed.answers, message.answers);
+    assert_eq!(QueryType::from_num(28), QueryType::AAAA);
+  }
+
+  #[test]
+  fn repeated_names_are_compressed() {
+    let answers: Vec<DnsRecord> = (1..=4).map(|i| a("www.example.test", i)).collect();
+    let (wire, parsed) = round_trip(&response(answers.clone()), u16::MAX as usize);
+    assert_eq!(parsed.answers, answers);
+    // the name is spelled out once, in the question; every answer points back at it
+    // with a two byte pointer: 12 header + 22 question + 4 * (2 + 10 + 4)
+    assert_eq!(wire.len(), 12 + 22 + 4 * 16);
+    let name = b"\x03www\x07example\x04test\x00";
+    assert_eq!(wire.windows(name.len()).filter(|w| w == name).count(), 1);
+  }
+
+  #[test]
+  fn names_are_compressed_case_insensitively_and_by_suffix() {
+    let answers = vec![
+      DnsRecord::CNAME {
+        domain: "WWW.Example.Test".into(),
+        host: "cdn.example.test".into(),
+        ttl: 60,
+      },
+      a("cdn.example.test", 1),
+    ];
+    let (wire, _) = round_trip(&response(answers), u16::MAX as usize);
+    // the CNAME's owner is all pointer, its target and the A's owner are one
+    // label and a pointer
+    assert_eq!(wire.len(), 12 + 22 + (2 + 10 + 6) + (2 + 10 + 4));
+  }
+
+  #[test]
+  fn names_follow_pointers() {
+    let mut packet = vec![0x12, 0x34, 0x01, 0x00, 0, 2, 0, 0, 0, 0, 0, 0];
+    packet.extend_from_slice(b"\x03www\x07Example\x04test\x00\x00\x01\x00\x01");
+    packet.extend_from_slice(b"\x04mail\xc0\x10\x00\x0f\x00\x01");
+    let mut message = DnsMessage::default();
+    message.parse(&packet).unwrap();
+    assert_eq!(
+      message.questions,
+      vec![
+        DnsQuestion::new("www.example.test", QueryType::A),
+        DnsQuestion::new("mail.example.test", QueryType::MX),
+      ]
+    );
+  }
+
+  #[test]
+  fn pointer_loops_are_errors() {
+    let mut packet = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    // a name that points at itself
+    packet.extend_from_slice(b"\xc0\x0c\x00\x01\x00\x01");
+    assert!(DnsMessage::default().parse(&packet).is_err());
+  }
+
+  #[test]
+  fn truncated_packets_are_errors() {
+    let wire = response(vec![a("www.example.test", 1)])
+      .to_wire(u16::MAX as usize)
+      .unwrap();
+    for len in [0, 5, 20, wire.len() - 1] {
+      assert!(
+        DnsMessage::default().parse(&wire[..len]).is_err(),
+        "{}",
+        len
+      );
+    }
+  }
+
+  #[test]
+  fn ns_and_ptr_targets_are_compressed() {
+    let records = vec![
+      DnsRecord::NS {
+        domain: "example.test".into(),
+        host: "ns1.example.test".into(),
+        ttl: 300,
+      },
+      DnsRecord::PTR {
+        domain: "1.0.0.10.in-addr.arpa".into(),
+        host: "www.example.test".into(),
+        ttl: 300,
+      },
+    ];
+    let (wire, parsed) = round_trip(&response(records.clone()), u16::MAX as usize);
+    assert_eq!(parsed.answers, records);
+    // the NS is one label and a pointer to example.test in the question, and its
+    // rdlength counts the compressed name, not the spelled out one
+    let ns = b"\xc0\x10\x00\x02\x00\x01\x00\x00\x01\x2c\x00\x06\x03ns1\xc0\x10";
+    assert_eq!(wire.windows(ns.len()).filter(|w| w == ns).count(), 1);
+    // the PTR's target is the question's name, so it's nothing but a pointer
+    let ptr = b"\x00\x0c\x00\x01\x00\x00\x01\x2c\x00\x02\xc0\x0c";
+    assert!(wire.ends_with(ptr));
+  }
+
+  #[test]
+  fn soa_names_are_compressed() {
+    let mut message = response(Vec::new());
+    message.flags.rcode = ResultCode::NXDOMAIN;
+    message.authorities = vec![DnsRecord::SOA {
+      domain: "example.test".into(),
+      m_name: "ns1.example.test".into(),
+      r_name: "hostmaster.example.test".into(),
+      serial: 2024010101,
+      refresh: 7200,
+      retry: 900,
+      expire: 1209600,
+      minimum: 300,
+      ttl: 3600,
+    }];
+    let (wire, parsed) = round_trip(&message, UDP_PAYLOAD_SIZE);
+    assert_eq!(parsed.flags.rcode, ResultCode::NXDOMAIN);
+    assert_eq!(parsed.authorities, message.authorities);
+    // both names are a label and a pointer, and rdlength is what they came to:
+    // 6 + 13 + five 4 byte fields
+    let mut soa = b"\xc0\x10\x00\x06\x00\x01\x00\x00\x0e\x10\x00\x27".to_vec();
+    soa.extend_from_slice(b"\x03ns1\xc0\x10\x0ahostmaster\xc0\x10");
+    for field in [2024010101u32, 7200, 900, 1209600, 300] {
+      soa.extend_from_slice(&field.to_be_bytes());
+    }
+    assert!(wire.ends_with(&soa));
+  }
+
+  #[test]
+  fn every_section_is_counted_and_kept_apart() {
+    let mut message = response(vec![a("www.example.test", 1), a("www.example.test", 2)]);
+    message.authorities = vec![DnsRecord::NS {
+      domain: "example.test".into(),
+      host: "ns1.example.test".into(),
+      ttl: 3600,
+    }];
+    message.additionals = vec![a("ns1.example.test", 53), a("ns2.example.test", 54)];
+    let (wire, parsed) = round_trip(&message, u16::MAX as usize);
+    // qdcount, ancount, nscount and arcount
+    assert_eq!(wire[4..12], [0, 1, 0, 2, 0, 1, 0, 2]);
+    assert_eq!(parsed.questions, message.questions);
+    assert_eq!(parsed.answers, message.answers);
+    assert_eq!(parsed.authorities, message.authorities);
+    assert_eq!(parsed.additionals, message.additionals);
+
+    // a header that promises more records than there are is an error
+    let mut short = wire.clone();
+    short[11] = 3;
+    assert!(DnsMessage::default().parse(&short).is_err());
+  }
+
+  #[test]
+  fn records_survive_a_round_trip() {
+    let records = vec![
+      a("www.example.test", 1),
+      DnsRecord::AAAA {
+        domain: "www.example.test".into(),
+        addr: "fd00::1".parse().unwrap(),
+        ttl: 300,
+      },
+      DnsRecord::CNAME {
+        domain: "alias.example.test".into(),
+        host: "www.example.test".into(),
+        ttl: 60,
+      },
+      DnsRecord::MX {
+        domain: "example.test".into(),
+        priority: 10,
+        host: "mail.example.test".into(),
+        ttl: 60,
+      },
+      DnsRecord::TXT {
+        domain: "example.test".into(),
+        data: vec![b"v=spf1 -all".to_vec(), Vec::new()],
+        ttl: 60,
+      },
+      DnsRecord::SRV {
+        domain: "_sip._udp.example.test".into(),
+        priority: 1,
+        weight: 2,
+        port: 5060,
+        host: "sip.example.test".into(),
+        ttl: 60,
+      },
+      DnsRecord::CAA {
+        domain: "example.test".into(),
+        flags: 0,
+        tag: "issue".into(),
+        value: b"ca.example".to_vec(),
+        ttl: 60,
+      },
+      DnsRecord::UNKNOWN {
+        domain: "example.test".into(),
+        qtype: 65,
+        class: 1,
+        data: vec![1, 2, 3],
+        ttl: 60,
+      },
+    ];
+    let (_, parsed) = round_trip(&response(records.clone()), u16::MAX as usize);
+    assert_eq!(parsed.tx_id, 0xbeef);
+    assert_eq!(parsed.flags.rq, DnsMessageType::Response);
+    assert!(parsed.flags.recursive);
+    assert_eq!(
+      parsed.questions,
+      vec![DnsQuestion::new("www.example.test", QueryType::A)]
+    );
+    assert_eq!(parsed.answers, records);
+  }
+
+  #[test]
+  fn records_outside_class_in_keep_their_class() {
+    let mut packet = vec![0x12, 0x34, 0x84, 0x00, 0, 1, 0, 1, 0, 1, 0, 0];
+    packet.extend_from_slice(b"\x07version\x04bind\x00\x00\x10\x00\x03");
+    // CH TXT "1.0", and a CH NS whose target is a pointer back to the question
+    packet.extend_from_slice(b"\xc0\x0c\x00\x10\x00\x03\x00\x00\x00\x00\x00\x04\x031.0");
+    packet.extend_from_slice(b"\xc0\x0c\x00\x02\x00\x03\x00\x00\x00\x00\x00\x02\xc0\x0c");
+    let mut message = DnsMessage::default();
+    message.parse(&packet).unwrap();
+    assert_eq!(
+      message.answers,
+      vec![DnsRecord::UNKNOWN {
+        domain: "version.bind".into(),
+        qtype: QueryType::TXT.to_num(),
+        class: 3,
+        data: b"\x031.0".to_vec(),
+        ttl: 0,
+      }]
+    );
+    // the pointer only meant something in the packet it came in, so the name is
+    // spelled out in full
+    assert_eq!(
+      message.authorities,
+      vec![DnsRecord::UNKNOWN {
+        domain: "version.bind".into(),
+        qtype: QueryType::NS.to_num(),
+        class: 3,
+        data: b"\x07version\x04bind\x00".to_vec(),
+        ttl: 0,
+      }]
+    );
+
+    let (_, parsed) = round_trip(&message, UDP_PAYLOAD_SIZE);
+    assert_eq!(parsed.answers, message.answers);
+    assert_eq!(parsed.authorities, message.authorities);
+  }
+
+  #[test]
+  fn unencodable_records_are_errors() {
+    let long_tag = DnsRecord::CAA {
+      domain: "example.test".into(),
+      flags: 0,
+      tag: "a".repeat(256),
+      value: Vec::new(),
+      ttl: 60,
+    };
+    let empty_tag = DnsRecord::CAA {
+      domain: "example.test".into(),
+      flags: 0,
+      tag: String::new(),
+      value: b"ca.example".to_vec(),
+      ttl: 60,
+    };
+    let long_txt = DnsRecord::TXT {
+      domain: "example.test".into(),
+      data: vec![vec![b'x'; 256]],
+      ttl: 60,
+    };
+    for record in [long_tag, empty_tag, long_txt] {
+      let mut buffer = PacketBuf::with_limit(u16::MAX as usize);
+      assert!(record.write(&mut buffer).is_err(), "{:?}", record);
+    }
+  }
+
+  #[test]
+  fn flags_round_trip() {
+    // unassigned rcodes (11-15) and opcodes (3, 6-15) come back out as they went in
+    for raw in [
+      0x0120u16, 0x8180, 0x8583, 0x0100, 0x2800, 0x8010, 0x818b, 0x810f, 0x1800, 0x7800,
+    ] {
+      assert_eq!(Flags::new(raw).to_u16(), raw, "{:#06x}", raw);
+    }
+    assert_eq!(Flags::new(0x818b).rcode, ResultCode::UNKNOWN(11));
+    assert_eq!(Flags::new(0x1800).opcode, Opcode::UNKNOWN(3));
+  }
+
+  #[test]
+  fn opt_records_are_parsed_out_of_the_additionals() {
+    let mut query = DnsMessage::default();
+    query.parse(&edns_query(1232, 0x0000_8000)).unwrap();
+    assert!(query.additionals.is_empty());
+    assert_eq!(
+      query.edns,
+      Some(Edns {
+        payload_size: 1232,
+        extended_rcode: 0,
+        version: 0,
+        dnssec_ok: true,
+        options: vec![EdnsOption {
+          code: 10,
+          data: b"cookie!!".to_vec(),
+        }],
+      })
+    );
+    assert_eq!(query.max_udp_payload(), 1232);
+
+    // too small is bumped up to 512, too big is cut down to what we'll send
+    let mut query = DnsMessage::default();
+    query.parse(&edns_query(100, 0)).unwrap();
+    assert_eq!(query.max_udp_payload(), UDP_PAYLOAD_SIZE);
+    let mut query = DnsMessage::default();
+    query.parse(&edns_query(65000, 0)).unwrap();
+    assert_eq!(query.max_udp_payload(), EDNS_PAYLOAD_SIZE as usize);
+
+    // no edns, no more than 512
+    assert_eq!(DnsMessage::default().max_udp_payload(), UDP_PAYLOAD_SIZE);
+  }
+
+  #[test]
+  fn two_opt_records_are_an_error() {
+    let mut packet = edns_query(1232, 0);
+    packet[11] = 2;
+    packet.extend_from_slice(b"\x00\x00\x29\x04\xd0\x00\x00\x00\x00\x00\x00");
+    assert!(DnsMessage::default().parse(&packet).is_err());
+  }
+
+  #[test]
+  fn oversized_edns_options_are_errors() {
+    let edns = Edns {
+      options: vec![EdnsOption {
+        code: 10,
+        data: vec![0; 0x10000],
+      }],
+      ..Edns::default()
+    };
+    // room enough for it all, so the length is what fails and not BufferFull,
+    // which would have a udp answer fall back to TC
+    let mut buffer = PacketBuf::with_limit(0x20000);
+    assert!(matches!(edns.write(&mut buffer), Err(DnsError::Other(_))));
+  }
+
+  #[test]
+  fn responses_echo_edns() {
+    let mut query = DnsMessage::default();
+    query.parse(&edns_query(1232, 0x0000_8000)).unwrap();
+    let response = query.generate_response().unwrap();
+    // ours, with the DO bit echoed and the options we don't speak dropped
+    assert_eq!(
+      response.edns,
+      Some(Edns {
+        dnssec_ok: true,
+        ..Edns::default()
+      })
+    );
+    let (_, parsed) = round_trip(response, UDP_PAYLOAD_SIZE);
+    assert_eq!(parsed.edns, response.edns);
+
+    // versions past 0 get BADVERS
+    let mut query = DnsMessage::default();
+    query.parse(&edns_query(1232, 0x0001_0000)).unwrap();
+    let response = query.generate_response().unwrap();
+    assert_eq!(response.edns.as_ref().unwrap().extended_rcode, 1);
+    assert_eq!(response.edns.as_ref().unwrap().version, 0);
+
+    // and nobody gets edns without asking for it
+    let mut query = DnsMessage::default();
+    assert!(query.generate_response().unwrap().edns.is_none());
+  }
+
+  #[test]
+  fn oversized_udp_answers_fall_back_to_tc() {
+    let answers: Vec<DnsRecord> = (0..40)
+      .map(|i| a(&format!("host{}.example.test", i), i))
+      .collect();
+    let message = response(answers.clone());
+
+    let wire = message.to_response(UDP_PAYLOAD_SIZE, true).unwrap();
+    assert!(wire.len() <= UDP_PAYLOAD_SIZE);
+    let mut parsed = DnsMessage::default();
+    parsed.parse(&wire).unwrap();
+    assert!(parsed.flags.truncated);
+    assert_eq!(parsed.flags.rcode, ResultCode::NOERROR);
+    assert!(parsed.answers.is_empty());
+    assert_eq!(parsed.questions, message.questions);
+
+    // over tcp it all fits
+    let wire = message.to_response(u16::MAX as usize, false).unwrap();
+    let mut parsed = DnsMessage::default();
+    parsed.parse(&wire).unwrap();
+    assert!(!parsed.flags.truncated);
+    assert_eq!(parsed.answers, answers);
+  }
+
+  #[test]
+  fn tcp_answers_are_never_truncated() {
+    let answers = (0..300)
+      .map(|_| DnsRecord::TXT {
+        domain: "www.example.test".into(),
+        data: vec![vec![b'x'; 255]],
+        ttl: 60,
+      })
+      .collect();
+    let wire = response(answers)
+      .to_response(u16::MAX as usize, false)
+      .unwrap();
+    let mut parsed = DnsMessage::default();
+    parsed.parse(&wire).unwrap();
+    assert!(!parsed.flags.truncated);
+    assert_eq!(parsed.flags.rcode, ResultCode::SERVFAIL);
+    assert!(parsed.answers.is_empty());
+  }
+
+  #[test]
+  fn unencodable_answers_are_servfail() {
+    let empty_tag = response(vec![DnsRecord::CAA {
+      domain: "www.example.test".into(),
+      flags: 0,
+      tag: String::new(),
+      value: b"ca.example".to_vec(),
+      ttl: 60,
+    }]);
+
+    // a 63 byte label that isn't utf-8 reads fine, but comes out too long to write
+    let mut packet = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+    packet.extend_from_slice(b"\x03www\x07example\x04test\x00\x00\x01\x00\x01");
+    packet.push(63);
+    packet.extend_from_slice(&[0xff; 63]);
+    packet.extend_from_slice(b"\xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x3c\x00\x04\x0a\x00\x00\x01");
+    let mut mangled = DnsMessage::default();
+    mangled.parse(&packet).unwrap();
+
+    for message in [empty_tag, mangled] {
+      for udp in [true, false] {
+        let wire = message.to_response(UDP_PAYLOAD_SIZE, udp).unwrap();
+        let mut parsed = DnsMessage::default();
+        parsed.parse(&wire).unwrap();
+        assert!(!parsed.flags.truncated);
+        assert_eq!(parsed.flags.rcode, ResultCode::SERVFAIL);
+        assert!(parsed.answers.is_empty());
+        assert_eq!(parsed.questions, message.questions);
+      }
+    }
+  }
 
-  fn remove_dot(&self, value: String) -> String {
-    let mut chars = value.chars();
-    chars.next_back();
-    chars.as_str().to_string()
-  }
-
-  /*
-    hop along the byte array, grabbing chunks, and then incrementing our index, so the next call grabs the next specified length
-    and can increment the index to the start of the following one
-  */
-  fn take_next(
-    &self,
-    buf: &[u8],
-    current_index: &mut usize,
-    jump: usize,
-  ) -> Result<Vec<u8>, DnsError> {
-    // here we do have to get the NEXT one,
-    // because the current_index is just the position of the length of characters to read
-    *current_index += 1;
-    let ret = buf[*current_index..*current_index + jump].to_vec();
-    *current_index += jump;
-    Ok(ret)
+  #[test]
+  fn names_match_on_whole_labels() {
+    assert!(in_zone("www.example.test", "example.test"));
+    assert!(in_zone("example.test", "example.test"));
+    assert!(!in_zone("notexample.test", "example.test"));
+    assert!(!in_zone("example.test", "www.example.test"));
+    assert!(in_zone("anything", ""));
+    assert_eq!(normalize_name(" WWW.Example.Test. "), "www.example.test");
+    assert_eq!(normalize_name("."), "");
   }
 }
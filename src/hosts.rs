@@ -0,0 +1,235 @@
+use crate::dnsmessage::{normalize_name, DnsMessage, DnsQuestion, DnsRecord, Flags, QueryType};
+use std::{
+  collections::HashMap,
+  fs,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr},
+  path::PathBuf,
+};
+
+/// Names pinned to addresses in hosts files, like /etc/hosts, along with the
+/// reverse (PTR) records that go with them.
+#[derive(Debug, Default)]
+pub(crate) struct Hosts {
+  records: HashMap<String, Vec<DnsRecord>>,
+}
+
+impl Hosts {
+  /// Read every file in `paths`, in order. Files we can't read are logged and
+  /// skipped, as are lines we can't make sense of.
+  pub fn load(paths: &[PathBuf], ttl: u32) -> Hosts {
+    let mut hosts = Hosts::default();
+    for path in paths {
+      match fs::read_to_string(path) {
+        Ok(text) => {
+          let before = hosts.records.len();
+          hosts.parse(&text, ttl);
+          eprintln!(
+            "loaded {} names from hosts file {:?}",
+            hosts.records.len() - before,
+            path
+          );
+        }
+        Err(e) => eprintln!("couldn't read hosts file {:?}: {}", path, e),
+      }
+    }
+    hosts
+  }
+
+  /// Each line is an address followed by the names that go with it, and anything
+  /// after a `#` is a comment. The first name for an address is the one its
+  /// PTR record points at.
+  fn parse(&mut self, text: &str, ttl: u32) {
+    for line in text.lines() {
+      let line = line.split('#').next().unwrap_or_default();
+      let mut fields = line.split_whitespace();
+      let addr = match fields.next().map(|a| a.parse::<IpAddr>()) {
+        Some(Ok(addr)) => addr,
+        Some(Err(_)) => {
+          eprintln!(
+            "skipping hosts line {:?}, it doesn't start with an address",
+            line
+          );
+          continue;
+        }
+        None => continue,
+      };
+      let names: Vec<String> = fields
+        .map(normalize_name)
+        .filter(|n| !n.is_empty())
+        .collect();
+
+      if let Some(first) = names.first() {
+        let reverse = reverse_name(addr);
+        if !self.records.contains_key(&reverse) {
+          self.add(DnsRecord::PTR {
+            domain: reverse,
+            host: first.clone(),
+            ttl,
+          });
+        }
+      }
+      for domain in names {
+        self.add(match addr {
+          IpAddr::V4(addr) => DnsRecord::A { domain, addr, ttl },
+          IpAddr::V6(addr) => DnsRecord::AAAA { domain, addr, ttl },
+        });
+      }
+    }
+  }
+
+  fn add(&mut self, record: DnsRecord) {
+    let rrs = self.records.entry(record.domain().to_string()).or_default();
+    if !rrs.contains(&record) {
+      rrs.push(record);
+    }
+  }
+
+  /// Our answer to `question`, if it's for a name in a hosts file. The name is
+  /// ours entirely, so asking for a type we don't have gets an empty answer
+  /// rather than going elsewhere.
+  pub fn lookup(&self, question: &DnsQuestion) -> Option<DnsMessage> {
+    if question.class != 1 {
+      return None;
+    }
+    let rrs = self.records.get(&question.name)?;
+    Some(DnsMessage {
+      flags: Flags {
+        authoritative: true,
+        ..Flags::default()
+      },
+      answers: rrs
+        .iter()
+        .filter(|r| question.qtype == QueryType::ANY || r.qtype() == question.qtype)
+        .cloned()
+        .collect(),
+      ..DnsMessage::default()
+    })
+  }
+}
+
+/// The name a reverse lookup for `addr` asks about: 4.3.2.1.in-addr.arpa for
+/// 1.2.3.4, and one label per nibble under ip6.arpa for ipv6 (RFC 3596)
+fn reverse_name(addr: IpAddr) -> String {
+  match addr {
+    IpAddr::V4(a) => reverse_v4(a),
+    IpAddr::V6(a) => reverse_v6(a),
+  }
+}
+
+fn reverse_v4(addr: Ipv4Addr) -> String {
+  let [a, b, c, d] = addr.octets();
+  format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+}
+
+fn reverse_v6(addr: Ipv6Addr) -> String {
+  let mut name = String::new();
+  for byte in addr.octets().iter().rev() {
+    name.push_str(&format!("{:x}.{:x}.", byte & 0x0f, byte >> 4));
+  }
+  name.push_str("ip6.arpa");
+  name
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn hosts(text: &str) -> Hosts {
+    let mut hosts = Hosts::default();
+    hosts.parse(text, 60);
+    hosts
+  }
+
+  #[test]
+  fn names_get_their_addresses() {
+    let hosts = hosts(
+      "# comment\n10.0.0.1 NAS.lan nas # trailing\nfd00::1 nas.lan\nnot-an-address foo\n\n10.0.0.1 nas.lan\n",
+    );
+    let a = hosts
+      .lookup(&DnsQuestion::new("nas.lan", QueryType::A))
+      .unwrap();
+    assert!(a.flags.authoritative);
+    // the repeat of the first line adds nothing
+    assert_eq!(
+      a.answers,
+      vec![DnsRecord::A {
+        domain: "nas.lan".into(),
+        addr: Ipv4Addr::new(10, 0, 0, 1),
+        ttl: 60,
+      }]
+    );
+    assert_eq!(
+      hosts
+        .lookup(&DnsQuestion::new("nas", QueryType::A))
+        .unwrap()
+        .answers
+        .len(),
+      1
+    );
+    assert_eq!(
+      hosts
+        .lookup(&DnsQuestion::new("nas.lan", QueryType::ANY))
+        .unwrap()
+        .answers
+        .len(),
+      2
+    );
+    assert!(hosts
+      .lookup(&DnsQuestion::new("foo", QueryType::A))
+      .is_none());
+  }
+
+  #[test]
+  fn names_we_have_are_ours_for_every_type() {
+    let hosts = hosts("10.0.0.1 nas.lan\n");
+    let mx = hosts
+      .lookup(&DnsQuestion::new("nas.lan", QueryType::MX))
+      .unwrap();
+    assert!(mx.answers.is_empty());
+    assert!(hosts
+      .lookup(&DnsQuestion::new("other.lan", QueryType::A))
+      .is_none());
+    let chaos = DnsQuestion {
+      class: 3,
+      ..DnsQuestion::new("nas.lan", QueryType::A)
+    };
+    assert!(hosts.lookup(&chaos).is_none());
+  }
+
+  #[test]
+  fn addresses_point_back_at_their_first_name() {
+    let hosts = hosts("10.0.0.1 nas.lan nas\n10.0.0.1 other.lan\nfd00::1 v6.lan\n");
+    let ptr = |name: &str| {
+      hosts
+        .lookup(&DnsQuestion::new(name, QueryType::PTR))
+        .unwrap()
+        .answers
+    };
+    assert_eq!(
+      ptr("1.0.0.10.in-addr.arpa"),
+      vec![DnsRecord::PTR {
+        domain: "1.0.0.10.in-addr.arpa".into(),
+        host: "nas.lan".into(),
+        ttl: 60,
+      }]
+    );
+    let v6 = "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.d.f.ip6.arpa";
+    assert_eq!(ptr(v6).len(), 1);
+  }
+
+  #[test]
+  fn reverse_names() {
+    assert_eq!(
+      reverse_name("192.0.2.1".parse().unwrap()),
+      "1.2.0.192.in-addr.arpa"
+    );
+    assert_eq!(
+      reverse_v6("2001:db8::567:89ab".parse().unwrap()),
+      "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+    );
+    assert_eq!(
+      reverse_v6(Ipv6Addr::UNSPECIFIED),
+      format!("{}ip6.arpa", "0.".repeat(32))
+    );
+  }
+}
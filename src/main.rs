@@ -1,14 +1,17 @@
+mod blocklist;
+mod cache;
 mod config;
 mod dnserror;
 mod dnsmessage;
+mod hosts;
 mod server;
-use config::Config;
+#[cfg(test)]
+mod testing;
+mod upstream;
+mod view;
+mod zone;
 use socket2::{Domain, Protocol, Socket, Type};
-use std::{
-  env,
-  ffi::CString,
-  net::{IpAddr, Ipv4Addr, SocketAddr},
-};
+use std::{env, ffi::CString, sync::Arc, thread, time::Duration};
 
 fn main() {
   let args: Vec<String> = env::args().collect();
@@ -25,22 +28,74 @@ fn main() {
       std::process::exit(0);
     }
   }
-  let cr = config::Config::load("config".to_string());
+  let mut cr = config::Config::load("config".to_string());
+  if let (Ok(c), Some(i)) = (&mut cr, args.iter().position(|a| a == "--upstream")) {
+    match args.get(i + 1).and_then(|u| config::parse_upstream(u)) {
+      Some(u) => c.upstreams = vec![u],
+      None => {
+        eprintln!("--upstream needs an address, like 1.1.1.1 or 1.1.1.1:53");
+        std::process::exit(1);
+      }
+    }
+  }
   let socket = match Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp())) {
     Ok(a) => a,
     _ => panic!("couldn't create socket :("),
   };
+  let tcp_socket = match Socket::new(Domain::ipv4(), Type::stream(), Some(Protocol::tcp())) {
+    Ok(a) => a,
+    _ => panic!("couldn't create tcp socket :("),
+  };
   match cr {
     Ok(c) => {
       if !c.interface.is_empty() {
         socket
           .bind_device(Some(&CString::new(c.interface.clone()).unwrap()))
           .unwrap_or_else(|_| panic!("couldn't bind to {}", c.interface));
+        tcp_socket
+          .bind_device(Some(&CString::new(c.interface.clone()).unwrap()))
+          .unwrap_or_else(|_| panic!("couldn't bind to {}", c.interface));
       }
       socket
         .bind(&c.ip_address.into())
         .unwrap_or_else(|_| panic!("couldn't bind to {}", c.ip_address));
-      if server::service_loop(socket, c).is_ok() {
+      tcp_socket
+        .set_reuse_address(true)
+        .unwrap_or_else(|_| panic!("couldn't set SO_REUSEADDR on {}", c.ip_address));
+      tcp_socket
+        .bind(&c.ip_address.into())
+        .unwrap_or_else(|_| panic!("couldn't bind to {}", c.ip_address));
+      tcp_socket
+        .listen(128)
+        .unwrap_or_else(|_| panic!("couldn't listen on {}", c.ip_address));
+      let s = Arc::new(server::Server::new(c));
+      s.load_cache();
+      // before any threads start, so they all inherit the blocked signals
+      let signals = block_shutdown_signals();
+      let shutdown_server = s.clone();
+      thread::spawn(move || {
+        let sig = wait_for_signal(&signals);
+        eprintln!("caught signal {}, shutting down", sig);
+        shutdown_server.save_cache();
+        std::process::exit(0);
+      });
+      if s.config.cache_file.is_some() && s.config.cache_save_interval > 0 {
+        let save_server = s.clone();
+        thread::spawn(move || loop {
+          thread::sleep(Duration::from_secs(save_server.config.cache_save_interval));
+          save_server.save_cache();
+        });
+      }
+      let tcp_server = s.clone();
+      thread::spawn(move || server::tcp_loop(tcp_socket, tcp_server));
+      let probe_server = s.clone();
+      thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(
+          probe_server.config.upstream_probe_interval.max(1),
+        ));
+        probe_server.probe_upstreams();
+      });
+      if server::service_loop(socket, s).is_ok() {
         std::process::exit(0)
       } else {
         std::process::exit(1)
@@ -53,6 +108,27 @@ fn main() {
   }
 }
 
+/// Block SIGINT and SIGTERM so that instead of killing us outright, they wait for
+/// `wait_for_signal` to pick them up and shut us down cleanly.
+fn block_shutdown_signals() -> libc::sigset_t {
+  unsafe {
+    let mut set: libc::sigset_t = std::mem::zeroed();
+    libc::sigemptyset(&mut set);
+    libc::sigaddset(&mut set, libc::SIGINT);
+    libc::sigaddset(&mut set, libc::SIGTERM);
+    libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+    set
+  }
+}
+
+fn wait_for_signal(set: &libc::sigset_t) -> i32 {
+  let mut sig = 0;
+  unsafe {
+    libc::sigwait(set, &mut sig);
+  }
+  sig
+}
+
 fn help() {
   println!("dnsrs --upstream <upstream resolver> --interface <interface to bind to> --ip <ip address> --port <port> --config <config location>");
   println!(
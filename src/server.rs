@@ -1,44 +1,1213 @@
 use crate::{
+  blocklist::Blocklist,
+  cache::{Cache, Claim},
   config::Config,
-  dnsmessage::{DnsMessage, DnsRecord, PacketBuf},
+  dnserror::DnsError,
+  dnsmessage::{
+    in_zone, DnsMessage, DnsMessageType, DnsQuestion, DnsRecord, Flags, Opcode, QueryType,
+    ResultCode, EDNS_PAYLOAD_SIZE, HEADER_LEN,
+  },
+  hosts::Hosts,
+  upstream::Forwarder,
+  view::{forward_rules, load_zones, View},
+  zone::Zone,
 };
-use socket2::{Domain, Protocol, Socket, Type};
+use socket2::{SockAddr, Socket};
+use std::{
+  collections::HashSet,
+  io::{ErrorKind, Read, Write},
+  net::TcpStream,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    mpsc::{self, TrySendError},
+    Arc, Mutex,
+  },
+  thread,
+  time::Duration,
+};
+
+/// How a query reached us, which decides how big our answer is allowed to be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Transport {
+  Udp,
+  Tcp,
+}
+
+/// What `resolve` made of a query
+enum Step {
+  // our answer, ready to go
+  Done(DnsMessage),
+  // none of our own data has it, or not all of it, so it's up to the upstreams of
+  // the view named
+  Forward(String, Option<Box<Chase>>),
+}
+
+/// A CNAME chain in our own data that leads somewhere only upstream knows about
+struct Chase {
+  // our answer as far as the chain goes
+  local: DnsMessage,
+  // the client's query, asking about where the chain leaves off instead
+  query: DnsMessage,
+}
+
+/// How far `start_query` got without going upstream
+enum Started {
+  // the bytes of our answer, ready to send
+  Answered(Vec<u8>),
+  Forward(Pending),
+}
+
+/// A query none of our own data could answer, waiting to go upstream
+struct Pending {
+  query: DnsMessage,
+  view_name: String,
+  chase: Option<Box<Chase>>,
+  // how big our answer is allowed to be, and whether it's going back over udp
+  limit: usize,
+  udp: bool,
+}
+
+impl Pending {
+  /// Ask upstream, and build the bytes of our answer from whatever it says
+  fn finish(mut self, server: &Arc<Server>) -> Option<Vec<u8>> {
+    // before generate_response swaps in our own edns
+    let chased = self.chase.is_some();
+    let answer = match self.chase.take() {
+      Some(chase) => fetch(server, &self.view_name, &chase.query).map(|a| chain(chase.local, a)),
+      None => fetch(server, &self.view_name, &self.query),
+    };
+    let response = self.query.generate_response().and_then(|r| {
+      let mut response = r.clone();
+      match answer {
+        Ok(answer) => {
+          // a chain that starts in our own data is ours to vouch for
+          let authoritative = chased && answer.flags.authoritative;
+          response.relay(answer);
+          response.flags.authoritative = authoritative;
+        }
+        Err(e) => {
+          eprintln!("couldn't forward {:?}: {}", response.questions, e);
+          response.flags.rcode = ResultCode::SERVFAIL;
+        }
+      }
+      response.to_response(self.limit, self.udp)
+    });
+    match response {
+      Ok(response) => Some(response),
+      Err(e) => {
+        eprintln!("couldn't build response: {:02x?}", e);
+        None
+      }
+    }
+  }
+}
+
+/// The longest CNAME chain we'll follow through our own data
+const MAX_CNAME_CHAIN: usize = 16;
+
+/// Everything the udp loop and the tcp threads share
+#[derive(Debug)]
+pub(crate) struct Server {
+  pub(crate) config: Config,
+  forwarder: Forwarder,
+  // conditional forwarding, longest suffix first so the first match is the best
+  forward_rules: Vec<(String, Forwarder)>,
+  cache: Cache,
+  // names pinned in hosts files, which win over everything else
+  hosts: Hosts,
+  // zones we're authoritative for, longest origin first
+  zones: Vec<Zone>,
+  // if there are any, every client has to fall into one of them
+  views: Vec<View>,
+  // names we won't resolve, unless our own data has them
+  blocklist: Blocklist,
+}
+
+impl Server {
+  pub(crate) fn new(config: Config) -> Server {
+    Server {
+      forwarder: Forwarder::new(config.upstreams.clone(), &config),
+      forward_rules: forward_rules(&config.forward_rules, &config),
+      cache: Cache::new(&config),
+      hosts: Hosts::load(&config.hosts_files, config.hosts_ttl),
+      zones: load_zones(&config.zones),
+      views: config.views.iter().map(|v| View::new(v, &config)).collect(),
+      blocklist: Blocklist::load(&config.blocklists, config.block_action, config.block_ttl),
+      config,
+    }
+  }
+
+  /// Which view `client` gets: the first with a network it's in. Without any
+  /// views everyone gets the global settings, but once there are some, clients
+  /// that aren't in any of them get nothing.
+  fn view_for(&self, client: &SockAddr) -> Result<Option<&View>, DnsError> {
+    if self.views.is_empty() {
+      return Ok(None);
+    }
+    let addr = client
+      .as_std()
+      .map(|a| a.ip())
+      .ok_or("client has no ip address")?;
+    match self.views.iter().find(|v| v.matches(addr)) {
+      Some(v) => Ok(Some(v)),
+      None => Err(format!("{} isn't in any view", addr).as_str().into()),
+    }
+  }
+
+  fn view_named(&self, name: &str) -> Option<&View> {
+    self.views.iter().find(|v| v.name == name)
+  }
+
+  /// The upstreams that should answer for `name`: those of the rule with the
+  /// longest matching suffix, the view's rules first, or the defaults if no rule
+  /// matches.
+  fn forwarder_for<'a>(&'a self, view: Option<&'a View>, name: &str) -> &'a Forwarder {
+    view
+      .and_then(|v| v.forwarder_for(name))
+      .or_else(|| {
+        self
+          .forward_rules
+          .iter()
+          .find(|(suffix, _)| in_zone(name, suffix))
+          .map(|(_, f)| f)
+      })
+      .unwrap_or(&self.forwarder)
+  }
+
+  /// Whether we'll go looking for answers we don't have ourselves: the view has
+  /// to allow it, and there has to be some upstream to ask
+  fn recursion_available(&self, view: Option<&View>) -> bool {
+    let recursion = view.map(|v| v.recursion).unwrap_or(true);
+    let views = view.iter().flat_map(|v| v.forward_rules.iter());
+    recursion
+      && (!self.forwarder.is_empty()
+        || self
+          .forward_rules
+          .iter()
+          .chain(views)
+          .any(|(_, f)| !f.is_empty()))
+  }
+
+  /// Our own answer to `question`, from the hosts files or our zones, with any
+  /// CNAMEs followed for as long as they lead to names we have locally. Where a
+  /// chain leaves our data we stop, and give back the question for where it went,
+  /// for upstream to pick up if this client gets recursion.
+  fn local_answer(
+    &self,
+    view: Option<&View>,
+    question: &DnsQuestion,
+  ) -> Option<(DnsMessage, Option<DnsQuestion>)> {
+    let mut answer = self.local_lookup(view, question)?;
+    let mut seen = HashSet::from([question.name.clone()]);
+    let mut next = cname_target(&answer, question);
+    while let Some(target) = next {
+      if !seen.insert(target.clone()) || seen.len() > MAX_CNAME_CHAIN {
+        eprintln!("CNAME loop at {} chasing {:?}", target, question);
+        answer.answers.clear();
+        answer.authorities.clear();
+        answer.flags.rcode = ResultCode::SERVFAIL;
+        answer.flags.authoritative = false;
+        break;
+      }
+      let q = DnsQuestion {
+        name: target,
+        ..question.clone()
+      };
+      let more = match self.local_lookup(view, &q) {
+        Some(more) => more,
+        None => return Some((answer, Some(q))),
+      };
+      next = cname_target(&more, &q);
+      // the rcode and authority are for the end of the chain (RFC 6604)
+      answer.flags.rcode = more.flags.rcode;
+      answer.answers.extend(more.answers);
+      answer.authorities = more.authorities;
+    }
+    Some((answer, None))
+  }
+
+  /// One step of `local_answer`: whatever a hosts file or zone says about
+  /// exactly this question
+  fn local_lookup(&self, view: Option<&View>, question: &DnsQuestion) -> Option<DnsMessage> {
+    self.hosts.lookup(question).or_else(|| {
+      self
+        .zone_for(view, &question.name)
+        .and_then(|z| z.lookup(question))
+    })
+  }
+
+  /// The most specific of our zones that `name` falls in, if any, looking in the
+  /// view's own zones before the global ones
+  fn zone_for<'a>(&'a self, view: Option<&'a View>, name: &str) -> Option<&'a Zone> {
+    view
+      .and_then(|v| v.zone_for(name))
+      .or_else(|| self.zones.iter().find(|z| in_zone(name, &z.origin)))
+  }
+
+  /// Our answer to `question` if its name is blocked, by the view's own lists or
+  /// the global ones
+  fn blocked(&self, view: Option<&View>, question: &DnsQuestion) -> Option<DnsMessage> {
+    view
+      .and_then(|v| v.blocked(question))
+      .or_else(|| self.blocklist.lookup(question))
+  }
+
+  /// Fill the cache from `cache_file`, if there is one yet
+  pub(crate) fn load_cache(&self) {
+    if let Some(path) = &self.config.cache_file {
+      if !path.exists() {
+        return;
+      }
+      match self.cache.load(path) {
+        Ok(n) => eprintln!("loaded {} cached answers from {:?}", n, path),
+        Err(e) => eprintln!("couldn't load cache from {:?}: {}", path, e),
+      }
+    }
+  }
 
-pub(crate) fn service_loop(s: Socket, c: Config) -> std::io::Result<()> {
+  /// Write the cache out to `cache_file`, if we have one
+  pub(crate) fn save_cache(&self) {
+    if let Some(path) = &self.config.cache_file {
+      match self.cache.save(path) {
+        Ok(n) => eprintln!("saved {} cached answers to {:?}", n, path),
+        Err(e) => eprintln!("couldn't save cache to {:?}: {}", path, e),
+      }
+    }
+  }
+
+  /// Check on any upstreams, in any rule, that have been marked down
+  pub(crate) fn probe_upstreams(&self) {
+    self.forwarder.probe_down();
+    let views = self.views.iter().flat_map(|v| v.forward_rules.iter());
+    for (_, f) in self.forward_rules.iter().chain(views) {
+      f.probe_down();
+    }
+  }
+}
+
+/// Receive udp queries and answer them. Anything our own data or the cache can
+/// answer is answered right here; the rest waits for one of `udp_workers` threads
+/// to take it upstream, so one waiting on a slow upstream doesn't hold up everyone
+/// behind it. Only `udp_max_queries` wait their turn at once; past that, new ones
+/// are dropped and the client will retry.
+pub(crate) fn service_loop(s: Socket, server: Arc<Server>) -> std::io::Result<()> {
   eprintln!("listening for dns requests...");
   eprintln!("{:?}", s);
-  eprintln!("{:?}", c);
-  let mut pktbuf = PacketBuf::new();
-  #[allow(unreachable_code)]
+  eprintln!("{:?}", server.config);
+  let s = Arc::new(s);
+  let (queue, waiting) = mpsc::sync_channel::<(Pending, SockAddr)>(server.config.udp_max_queries);
+  let waiting = Arc::new(Mutex::new(waiting));
+  for _ in 0..server.config.udp_workers.max(1) {
+    let (s, server, waiting) = (s.clone(), server.clone(), waiting.clone());
+    thread::spawn(move || loop {
+      let next = waiting.lock().unwrap().recv();
+      let (pending, client) = match next {
+        Ok(next) => next,
+        Err(_) => return,
+      };
+      if let Some(response) = pending.finish(&server) {
+        send_udp(&s, &response, &client);
+      }
+    });
+  }
+  let mut buf = [0u8; EDNS_PAYLOAD_SIZE as usize];
   loop {
-    let a = match s.recv_from(&mut pktbuf.buf) {
+    let (len, client) = match s.recv_from(&mut buf) {
       Ok(b) => b,
-      Err(_) => todo!(),
+      Err(e) => {
+        eprintln!("error receiving from socket: {:?}", e);
+        continue;
+      }
+    };
+    match start_query(&server, &buf[..len], &client, Transport::Udp) {
+      Some(Started::Answered(response)) => send_udp(&s, &response, &client),
+      Some(Started::Forward(pending)) => {
+        if let Err(TrySendError::Full((_, client))) = queue.try_send((pending, client)) {
+          eprintln!(
+            "too many udp queries waiting on upstream, dropping one from {:?}",
+            client
+          );
+        }
+      }
+      None => {}
+    }
+  }
+}
+
+fn send_udp(s: &Socket, response: &[u8], client: &SockAddr) {
+  if let Err(e) = s.send_to(response, client) {
+    eprintln!("error sending response to {:?}: {:?}", client, e);
+  }
+}
+
+/// Accept dns-over-tcp connections (RFC 7766), giving each its own thread so a slow
+/// client can't hold up anyone else. Only `tcp_max_connections` are served at once;
+/// anything past that is closed straight away (RFC 7766 6.2.2), so a pile of idle
+/// connections can't eat all our threads.
+pub(crate) fn tcp_loop(listener: Socket, server: Arc<Server>) -> std::io::Result<()> {
+  eprintln!("listening for dns requests over tcp...");
+  eprintln!("{:?}", listener);
+  let idle_timeout = Duration::from_secs(server.config.tcp_idle_timeout);
+  let open = Arc::new(AtomicUsize::new(0));
+  loop {
+    let (conn, client) = match listener.accept() {
+      Ok(c) => c,
+      Err(e) => {
+        eprintln!("error accepting tcp connection: {:?}", e);
+        continue;
+      }
+    };
+    if open.fetch_add(1, Ordering::SeqCst) >= server.config.tcp_max_connections {
+      open.fetch_sub(1, Ordering::SeqCst);
+      eprintln!(
+        "too many tcp connections, closing the one from {:?}",
+        client
+      );
+      continue;
+    }
+    let server = server.clone();
+    let open = open.clone();
+    thread::spawn(move || {
+      if let Err(e) = serve_tcp(&server, conn.into_tcp_stream(), &client, idle_timeout) {
+        eprintln!("error serving tcp client {:?}: {:?}", client, e);
+      }
+      open.fetch_sub(1, Ordering::SeqCst);
+    });
+  }
+}
+
+/// Answer queries on a tcp connection until the client hangs up or goes quiet for
+/// `idle_timeout`. Clients are allowed to pipeline, sending their next query before
+/// we've answered the last; we just take them in order. A client that stops
+/// reading its answers gets `idle_timeout` to start again before we give up on it,
+/// and so does one that sends something we can't answer at all.
+///
+/// Messages over tcp are prefixed with their length as two bytes (RFC 1035 4.2.2).
+fn serve_tcp(
+  server: &Arc<Server>,
+  mut stream: TcpStream,
+  client: &SockAddr,
+  idle_timeout: Duration,
+) -> std::io::Result<()> {
+  stream.set_read_timeout(Some(idle_timeout))?;
+  stream.set_write_timeout(Some(idle_timeout))?;
+  loop {
+    let mut len_buf = [0u8; 2];
+    match stream.read_exact(&mut len_buf) {
+      Ok(()) => {}
+      // the client closed the connection, or sat idle for too long
+      Err(e)
+        if e.kind() == ErrorKind::UnexpectedEof
+          || e.kind() == ErrorKind::WouldBlock
+          || e.kind() == ErrorKind::TimedOut =>
+      {
+        return Ok(())
+      }
+      Err(e) => return Err(e),
+    }
+    let mut query = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+    stream.read_exact(&mut query)?;
+    let response = match handle_query(server, &query, client, Transport::Tcp) {
+      Some(response) => response,
+      // they'd only sit there waiting on an answer that's never coming
+      None => return Ok(()),
+    };
+    let mut framed = Vec::with_capacity(response.len() + 2);
+    framed.extend_from_slice(&(response.len() as u16).to_be_bytes());
+    framed.extend_from_slice(&response);
+    stream.write_all(&framed)?;
+  }
+}
+
+/// Parse a query and build the bytes of our answer to it, going upstream if we
+/// have to. Tcp comes through here; udp splits the two halves between the
+/// receiving thread and the workers.
+pub(crate) fn handle_query(
+  server: &Arc<Server>,
+  query: &[u8],
+  client: &SockAddr,
+  transport: Transport,
+) -> Option<Vec<u8>> {
+  match start_query(server, query, client, transport)? {
+    Started::Answered(response) => Some(response),
+    Started::Forward(pending) => pending.finish(server),
+  }
+}
+
+/// Parse a query and answer it as far as we can without going upstream. Anything
+/// we can't make sense of gets logged and dropped, and so does anything that's
+/// already a response, so we can't be used to bounce packets. Over tcp, a query
+/// whose header we can read but nothing past it gets a FORMERR.
+fn start_query(
+  server: &Arc<Server>,
+  query: &[u8],
+  client: &SockAddr,
+  transport: Transport,
+) -> Option<Started> {
+  let mut message: DnsMessage = DnsMessage::default();
+  match message.parse(query) {
+    Ok(m) if m.flags.rq == DnsMessageType::Response => {
+      eprintln!(
+        "dropping a response from {:?}, we only answer queries",
+        client
+      );
+      None
+    }
+    Ok(m) => {
+      eprintln!(
+        "received {:#?} bytes over {:?} from client {:#?}",
+        query.len(),
+        transport,
+        client
+      );
+      let (limit, udp) = match transport {
+        Transport::Udp => (m.max_udp_payload(), true),
+        Transport::Tcp => (u16::MAX as usize, false),
+      };
+      match resolve(server, m, client) {
+        Ok(Step::Done(response)) => match response.to_response(limit, udp) {
+          Ok(response) => Some(Started::Answered(response)),
+          Err(e) => {
+            eprintln!("couldn't build response: {:02x?}", e);
+            None
+          }
+        },
+        Ok(Step::Forward(view_name, chase)) => Some(Started::Forward(Pending {
+          query: message,
+          view_name,
+          chase,
+          limit,
+          udp,
+        })),
+        Err(e) => {
+          eprintln!("couldn't build response: {:02x?}", e);
+          None
+        }
+      }
+    }
+    Err(e) => {
+      eprintln!("{:02x?}", e);
+      match transport {
+        Transport::Tcp => format_error(query).map(Started::Answered),
+        Transport::Udp => None,
+      }
+    }
+  }
+}
+
+/// A FORMERR for a query we couldn't parse, built from nothing but its header, or
+/// None if there isn't even a header to go on (RFC 1035 4.1.1)
+fn format_error(query: &[u8]) -> Option<Vec<u8>> {
+  if query.len() < usize::from(HEADER_LEN) {
+    return None;
+  }
+  let flags = Flags::new(u16::from_be_bytes([query[2], query[3]]));
+  if flags.rq == DnsMessageType::Response {
+    return None;
+  }
+  let response = DnsMessage {
+    tx_id: u16::from_be_bytes([query[0], query[1]]),
+    flags: Flags {
+      rq: DnsMessageType::Response,
+      opcode: flags.opcode,
+      recursive: flags.recursive,
+      checking_disabled: flags.checking_disabled,
+      rcode: ResultCode::FORMERR,
+      ..Flags::default()
+    },
+    ..DnsMessage::default()
+  };
+  response.to_wire(u16::MAX as usize).ok()
+}
+
+/// Work out the answer to a parsed query from `client`, short of going upstream
+fn resolve(
+  server: &Arc<Server>,
+  query: &mut DnsMessage,
+  client: &SockAddr,
+) -> Result<Step, DnsError> {
+  let view = match server.view_for(client) {
+    Ok(view) => view,
+    Err(e) => {
+      eprintln!("refusing query: {}", e);
+      let mut response = query.generate_response()?.clone();
+      response.flags.rcode = ResultCode::REFUSED;
+      response.flags.recursion_available = false;
+      return Ok(Step::Done(response));
+    }
+  };
+  let view_name = view.map(|v| v.name.as_str()).unwrap_or_default();
+  let recursion = server.recursion_available(view);
+
+  // work out the answer before generate_response swaps in our own edns
+  let well_formed = query.flags.opcode == Opcode::QUERY
+    && query.edns.as_ref().map(|e| e.version == 0).unwrap_or(true);
+  let answerable = well_formed && query.questions.len() == 1;
+  let (local, onward) = if answerable {
+    match server.local_answer(view, &query.questions[0]) {
+      Some((answer, onward)) => (Some(answer), onward),
+      None => (server.blocked(view, &query.questions[0]), None),
+    }
+  } else {
+    (None, None)
+  };
+  let authoritative = local
+    .as_ref()
+    .map(|a| a.flags.authoritative)
+    .unwrap_or(false);
+  let answer = match local {
+    // a chain out of our own data is followed upstream, for clients that get
+    // recursion (RFC 1034 4.3.2); the rest get it as far as our data goes
+    Some(local) => match onward {
+      Some(target) if recursion && !server.forwarder_for(view, &target.name).is_empty() => {
+        let query = DnsMessage {
+          questions: vec![target],
+          ..query.clone()
+        };
+        match server.cache.get(view_name, &query.questions[0]) {
+          Some(hit) => {
+            if hit.prefetch {
+              prefetch(server, view_name, &query);
+            }
+            Some(chain(local, hit.answer))
+          }
+          None => {
+            let chase = Box::new(Chase { local, query });
+            return Ok(Step::Forward(view_name.to_string(), Some(chase)));
+          }
+        }
+      }
+      _ => Some(local),
+    },
+    None if answerable => {
+      let forwarder = server.forwarder_for(view, &query.questions[0].name);
+      if !recursion || forwarder.is_empty() {
+        // it isn't ours, and either this client only gets to see our own data or
+        // there's nobody to ask about it
+        Some(DnsMessage {
+          flags: Flags {
+            rcode: ResultCode::REFUSED,
+            ..Flags::default()
+          },
+          ..DnsMessage::default()
+        })
+      } else {
+        match server.cache.get(view_name, &query.questions[0]) {
+          Some(hit) => {
+            if hit.prefetch {
+              prefetch(server, view_name, query);
+            }
+            Some(hit.answer)
+          }
+          None => return Ok(Step::Forward(view_name.to_string(), None)),
+        }
+      }
+    }
+    None => None,
+  };
+
+  let mut response = query.generate_response()?.clone();
+  response.flags.recursion_available = recursion;
+  match answer {
+    Some(answer) => {
+      response.relay(answer);
+      response.flags.authoritative = authoritative;
+    }
+    // a query has to have exactly one question (RFC 9619)
+    None if well_formed => response.flags.rcode = ResultCode::FORMERR,
+    None => {}
+  }
+  Ok(Step::Done(response))
+}
+
+/// Our own answer as far as its CNAME chain goes, followed by upstream's answer
+/// for where the chain left off. The rcode and authority section are upstream's,
+/// for the end of the chain, as in `Server::local_answer`.
+fn chain(mut local: DnsMessage, onward: DnsMessage) -> DnsMessage {
+  local.flags.rcode = onward.flags.rcode;
+  local.flags.authenticated = false;
+  local.answers.extend(onward.answers);
+  local.authorities = onward.authorities;
+  local
+}
+
+/// Where `answer` sends us next, if it's an alias for the name we asked about and
+/// we asked for something other than the alias itself
+fn cname_target(answer: &DnsMessage, question: &DnsQuestion) -> Option<String> {
+  if question.qtype == QueryType::CNAME || question.qtype == QueryType::ANY {
+    return None;
+  }
+  answer.answers.iter().find_map(|r| match r {
+    DnsRecord::CNAME { domain, host, .. } if *domain == question.name => Some(host.clone()),
+    _ => None,
+  })
+}
+
+/// Ask upstream, caching whatever comes back. If we've got an expired answer on
+/// hand, upstream only gets `cache_stale_answer_timeout` to beat it (RFC 8767
+/// section 5); after that the client gets the stale answer, and the lookup keeps
+/// going in the background to refresh the cache for next time. Only one lookup
+/// per entry goes to upstream at a time; anyone else gets the stale answer, or
+/// waits on the same lookup if there's nothing stale to give them.
+fn fetch(
+  server: &Arc<Server>,
+  view_name: &str,
+  query: &DnsMessage,
+) -> Result<DnsMessage, DnsError> {
+  let question = &query.questions[0];
+  let stale = match server.cache.get_stale(view_name, question) {
+    Some(stale) => stale,
+    None => return lookup(server, view_name, query),
+  };
+  if !server.cache.start_refresh(view_name, question) {
+    eprintln!("already refreshing, serving stale answer to {:?}", question);
+    return Ok(stale);
+  }
+  let (tx, rx) = mpsc::channel();
+  {
+    let server = server.clone();
+    let view_name = view_name.to_string();
+    let query = query.clone();
+    thread::spawn(move || {
+      let answer = refresh(&server, &view_name, &query);
+      server.cache.end_refresh(&view_name, &query.questions[0]);
+      // nobody's waiting any more if we took too long, which is fine
+      let _ = tx.send(answer);
+    });
+  }
+  let timeout = Duration::from_millis(server.config.cache_stale_answer_timeout);
+  match rx.recv_timeout(timeout) {
+    Ok(Ok(answer)) => Ok(answer),
+    Ok(Err(e)) => {
+      eprintln!(
+        "upstream failed ({}), serving stale answer to {:?}",
+        e, question
+      );
+      Ok(stale)
+    }
+    Err(_) => {
+      eprintln!("upstream is slow, serving stale answer to {:?}", question);
+      Ok(stale)
+    }
+  }
+}
+
+/// Go upstream for a question we've nothing cached for at all, unless someone
+/// else already is, in which case we wait for their answer instead. We only wait
+/// as long as asking upstream ourselves could have taken, and there's nothing
+/// stale to fall back on, so a lookup that gets stuck is a SERVFAIL.
+fn lookup(server: &Server, view_name: &str, query: &DnsMessage) -> Result<DnsMessage, DnsError> {
+  let question = &query.questions[0];
+  let owner = match server.cache.start_lookup(view_name, question) {
+    Claim::Owner(owner) => owner,
+    Claim::Waiter(lookup) => {
+      let view = server.view_named(view_name);
+      let patience = server.forwarder_for(view, &question.name).max_wait();
+      return lookup
+        .wait(patience)
+        .ok_or_else(|| "the lookup we were waiting on failed or timed out".into());
+    }
+  };
+  let answer = refresh(server, view_name, query);
+  owner.end(answer.as_ref().ok());
+  answer
+}
+
+/// Forward `query` to whoever answers for it in `view_name`, caching the answer
+fn refresh(server: &Server, view_name: &str, query: &DnsMessage) -> Result<DnsMessage, DnsError> {
+  let view = server.view_named(view_name);
+  let forwarder = server.forwarder_for(view, &query.questions[0].name);
+  let mut answer = forwarder.forward(query)?;
+  server
+    .cache
+    .insert(view_name, &query.questions[0], &mut answer);
+  Ok(answer)
+}
+
+/// Refresh a cached answer in the background, so whoever asks after it would have
+/// expired doesn't have to wait on upstream.
+fn prefetch(server: &Arc<Server>, view_name: &str, query: &DnsMessage) {
+  let server = server.clone();
+  let view_name = view_name.to_string();
+  let query = query.clone();
+  thread::spawn(move || {
+    if let Err(e) = refresh(&server, &view_name, &query) {
+      eprintln!("couldn't prefetch {:?}: {}", query.questions, e);
+    }
+    server.cache.end_refresh(&view_name, &query.questions[0]);
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    config::parse_forward_rule,
+    testing::{FakeUpstream, Fixture, SOA},
+    upstream::Upstream,
+    view::ViewConfig,
+  };
+  use socket2::{Domain, Protocol, Type};
+  use std::{
+    collections::BTreeSet,
+    net::{Ipv4Addr, SocketAddr, TcpListener, UdpSocket},
+    time::Instant,
+  };
+
+  /// The bytes of a query for `name`
+  fn query(tx_id: u16, name: &str, qtype: QueryType) -> Vec<u8> {
+    let mut message = DnsMessage {
+      tx_id,
+      questions: vec![DnsQuestion::new(name, qtype)],
+      ..DnsMessage::default()
     };
-    let mut message: DnsMessage = DnsMessage::default();
-    /*
-    for b in buf {
-      print!("{:016b} ", b);
-    }
-    */
-    println!();
-    match message.parse(&pktbuf.buf) {
-      Ok(mut _m) => {
-        eprintln!(
-          "received {:#?} bytes from socket from client {:#?}",
-          a.0, a.1
-        );
-        //println!("{:?}", _m);
-        // TODO
-        // respond to the message here
-        println!("query: {:02x?}", &_m);
-        let r = _m.generate_response();
-        let _x = r.unwrap().to_owned();
-        println!("response: {:02x?}", _x);
-      }
-      Err(e) => eprintln!("{:02x?}", e),
+    message.flags.recursive = true;
+    message.to_wire(u16::MAX as usize).unwrap()
+  }
+
+  fn parse(bytes: &[u8]) -> DnsMessage {
+    let mut message = DnsMessage::default();
+    message.parse(bytes).unwrap();
+    message
+  }
+
+  /// `server` answering udp on localhost, and a client socket to ask it with
+  fn udp_client(server: Server) -> UdpSocket {
+    let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp())).unwrap();
+    socket
+      .bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into())
+      .unwrap();
+    let addr = socket.local_addr().unwrap().as_std().unwrap();
+    let server = Arc::new(server);
+    thread::spawn(move || service_loop(socket, server));
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.connect(addr).unwrap();
+    client
+      .set_read_timeout(Some(Duration::from_secs(2)))
+      .unwrap();
+    client
+  }
+
+  fn receive(client: &UdpSocket) -> Option<DnsMessage> {
+    let mut buf = [0u8; 4096];
+    let len = client.recv(&mut buf).ok()?;
+    Some(parse(&buf[..len]))
+  }
+
+  fn upstream(fake: &FakeUpstream) -> Upstream {
+    Upstream {
+      addr: fake.addr,
+      timeout: None,
+    }
+  }
+
+  /// A connection to `server` as a tcp client would have it
+  fn tcp_client(server: Server, idle_timeout: Duration) -> TcpStream {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Arc::new(server);
+    thread::spawn(move || {
+      let (stream, client) = listener.accept().unwrap();
+      serve_tcp(&server, stream, &client.into(), idle_timeout).unwrap();
+    });
+    let stream = TcpStream::connect(addr).unwrap();
+    stream
+      .set_read_timeout(Some(Duration::from_secs(5)))
+      .unwrap();
+    stream
+  }
+
+  fn send_framed(stream: &mut TcpStream, message: &[u8]) {
+    stream
+      .write_all(&(message.len() as u16).to_be_bytes())
+      .unwrap();
+    stream.write_all(message).unwrap();
+  }
+
+  fn read_framed(stream: &mut TcpStream) -> Vec<u8> {
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).unwrap();
+    let mut message = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut message).unwrap();
+    message
+  }
+
+  /// Whether the other end has hung up on `stream`
+  fn closed(stream: &mut TcpStream) -> bool {
+    matches!(stream.read(&mut [0u8; 1]), Ok(0))
+  }
+
+  /// The forwarder `server` made for the rule with `suffix`
+  fn rule<'a>(server: &'a Server, suffix: &str) -> &'a Forwarder {
+    &server
+      .forward_rules
+      .iter()
+      .find(|(s, _)| s == suffix)
+      .unwrap()
+      .1
+  }
+
+  #[test]
+  fn the_longest_matching_suffix_wins() {
+    let server = Server::new(Config::with(|c| {
+      for rule in [
+        "example.test 10.0.0.1",
+        "lab.example.test 10.0.0.2",
+        ". 10.0.0.3",
+      ] {
+        c.forward_rules.push(parse_forward_rule(rule).unwrap());
+      }
+    }));
+    for (name, suffix) in [
+      ("www.example.test", "example.test"),
+      ("example.test", "example.test"),
+      ("box.lab.example.test", "lab.example.test"),
+      ("lab.example.test", "lab.example.test"),
+      // a suffix has to match whole labels
+      ("notexample.test", ""),
+      ("anything.else", ""),
+    ] {
+      assert!(
+        std::ptr::eq(server.forwarder_for(None, name), rule(&server, suffix)),
+        "{} should go to {:?}",
+        name,
+        suffix
+      );
+    }
+  }
+
+  #[test]
+  fn names_without_a_rule_go_to_the_default_upstreams() {
+    let server = Server::new(Config::with(|c| {
+      c.forward_rules
+        .push(parse_forward_rule("example.test 10.0.0.1").unwrap());
+    }));
+    assert!(std::ptr::eq(
+      server.forwarder_for(None, "www.other.test"),
+      &server.forwarder
+    ));
+  }
+
+  #[test]
+  fn local_cnames_are_chased() {
+    let text = format!(
+      "$TTL 60\n{}www CNAME web\nweb CNAME host\nhost A 10.0.0.1\nout CNAME www.elsewhere.test.\ndangling CNAME nothere\nloop1 CNAME loop2\nloop2 CNAME loop1\n",
+      SOA
+    );
+    let fixture = Fixture::new("cnames", &[("db", &text)]);
+    let server = Server::new(Config::with(|c| {
+      c.zones.push(fixture.zone_file("example.test", "db"))
+    }));
+    let ask = |name: &str, qtype: QueryType| {
+      let (answer, onward) = server
+        .local_answer(None, &DnsQuestion::new(name, qtype))
+        .unwrap();
+      assert!(onward.is_none());
+      answer
+    };
+
+    let www = ask("www.example.test", QueryType::A);
+    assert_eq!(www.flags.rcode, ResultCode::NOERROR);
+    assert!(www.flags.authoritative);
+    assert_eq!(
+      www
+        .answers
+        .iter()
+        .map(|r| (r.domain(), r.qtype()))
+        .collect::<Vec<_>>(),
+      vec![
+        ("www.example.test", QueryType::CNAME),
+        ("web.example.test", QueryType::CNAME),
+        ("host.example.test", QueryType::A),
+      ]
+    );
+
+    // asking for the alias itself doesn't go any further
+    assert_eq!(ask("www.example.test", QueryType::CNAME).answers.len(), 1);
+
+    // the chain leaves our data, so it's over to upstream from there
+    let (out, onward) = server
+      .local_answer(None, &DnsQuestion::new("out.example.test", QueryType::A))
+      .unwrap();
+    assert_eq!(out.flags.rcode, ResultCode::NOERROR);
+    assert_eq!(out.answers.len(), 1);
+    assert_eq!(
+      onward,
+      Some(DnsQuestion::new("www.elsewhere.test", QueryType::A))
+    );
+
+    // the rcode is for the end of the chain
+    let dangling = ask("dangling.example.test", QueryType::A);
+    assert_eq!(dangling.flags.rcode, ResultCode::NXDOMAIN);
+    assert_eq!(dangling.answers.len(), 1);
+
+    let looped = ask("loop1.example.test", QueryType::A);
+    assert_eq!(looped.flags.rcode, ResultCode::SERVFAIL);
+    assert!(!looped.flags.authoritative);
+    assert!(looped.answers.is_empty());
+  }
+
+  #[test]
+  fn cnames_out_of_our_data_are_followed_upstream() {
+    let text = format!("$TTL 60\n{}out CNAME www.elsewhere.test.\n", SOA);
+    let fixture = Fixture::new("cnames-out", &[("db", &text)]);
+    let fake = FakeUpstream::new(ResultCode::NOERROR);
+    let client = udp_client(Server::new(Config::with(|c| {
+      c.zones.push(fixture.zone_file("example.test", "db"));
+      c.upstreams = vec![upstream(&fake)];
+    })));
+    for tx_id in [1, 2] {
+      client
+        .send(&query(tx_id, "out.example.test", QueryType::A))
+        .unwrap();
+      let answer = receive(&client).unwrap();
+      assert_eq!(answer.tx_id, tx_id);
+      assert_eq!(answer.flags.rcode, ResultCode::NOERROR);
+      // the first name in the answer is ours
+      assert!(answer.flags.authoritative);
+      assert_eq!(
+        answer
+          .answers
+          .iter()
+          .map(|r| (r.domain(), r.qtype()))
+          .collect::<Vec<_>>(),
+        vec![
+          ("out.example.test", QueryType::CNAME),
+          ("www.elsewhere.test", QueryType::A),
+        ]
+      );
+    }
+    // and the second time round, the cache had the rest of it
+    assert_eq!(fake.queries(), 1);
+
+    // without anyone to ask, the client gets the chain as far as our data goes
+    let client = udp_client(Server::new(Config::with(|c| {
+      c.zones.push(fixture.zone_file("example.test", "db"))
+    })));
+    client
+      .send(&query(3, "out.example.test", QueryType::A))
+      .unwrap();
+    let answer = receive(&client).unwrap();
+    assert_eq!(answer.flags.rcode, ResultCode::NOERROR);
+    assert!(!answer.flags.recursion_available);
+    assert_eq!(answer.answers.len(), 1);
+  }
+
+  #[test]
+  fn clients_get_the_first_view_theyre_in() {
+    let server = Server::new(Config::with(|c| {
+      let mut lan = ViewConfig::new("lan".into(), vec!["10.0.0.0/8".parse().unwrap()]);
+      lan
+        .forward_rules
+        .push(parse_forward_rule("example.test 10.0.0.53").unwrap());
+      c.views.push(lan);
+      c.views.push(ViewConfig::new(
+        "everyone".into(),
+        vec!["0.0.0.0/0".parse().unwrap()],
+      ));
+      c.forward_rules
+        .push(parse_forward_rule("example.test 192.0.2.53").unwrap());
+    }));
+    let client = |addr: &str| SockAddr::from(addr.parse::<SocketAddr>().unwrap());
+    let view_of = |addr: &str| {
+      server
+        .view_for(&client(addr))
+        .unwrap()
+        .map(|v| v.name.clone())
     };
+    assert_eq!(view_of("10.1.2.3:5353"), Some("lan".to_string()));
+    assert_eq!(view_of("192.0.2.1:5353"), Some("everyone".to_string()));
+    assert!(server.view_for(&client("[::1]:5353")).is_err());
+
+    // the view's own rule comes before the global one for the same suffix
+    let lan = server.view_named("lan");
+    assert!(std::ptr::eq(
+      server.forwarder_for(lan, "www.example.test"),
+      &lan.unwrap().forward_rules[0].1
+    ));
+    let everyone = server.view_named("everyone");
+    assert!(std::ptr::eq(
+      server.forwarder_for(everyone, "www.example.test"),
+      rule(&server, "example.test")
+    ));
+  }
+
+  #[test]
+  fn tcp_clients_can_pipeline_until_they_go_quiet() {
+    let mut stream = tcp_client(
+      Server::new(Config::default().unwrap()),
+      Duration::from_millis(200),
+    );
+    // all three go out before we read any answers, and come back in order
+    for tx_id in 1..=3 {
+      send_framed(&mut stream, &query(tx_id, "www.example.test", QueryType::A));
+    }
+    for tx_id in 1..=3 {
+      let answer = parse(&read_framed(&mut stream));
+      assert_eq!(answer.tx_id, tx_id);
+      assert_eq!(answer.flags.rq, DnsMessageType::Response);
+    }
+    // then say nothing for longer than the idle timeout
+    thread::sleep(Duration::from_millis(400));
+    assert!(closed(&mut stream));
+  }
+
+  #[test]
+  fn tcp_clients_get_formerr_for_garbage() {
+    let mut stream = tcp_client(
+      Server::new(Config::default().unwrap()),
+      Duration::from_secs(5),
+    );
+    // a header promising a question that isn't there
+    let mut garbled = query(0x1234, "www.example.test", QueryType::A);
+    garbled.truncate(20);
+    send_framed(&mut stream, &garbled);
+    let answer = parse(&read_framed(&mut stream));
+    assert_eq!(answer.tx_id, 0x1234);
+    assert_eq!(answer.flags.rcode, ResultCode::FORMERR);
+    assert!(answer.flags.recursive);
+
+    // and the connection's still good for the next one
+    send_framed(&mut stream, &query(2, "www.example.test", QueryType::A));
+    assert_eq!(parse(&read_framed(&mut stream)).tx_id, 2);
+
+    // not even a header, so there's nothing to answer and we hang up
+    send_framed(&mut stream, b"\x12\x34\x01");
+    assert!(closed(&mut stream));
+  }
+
+  #[test]
+  fn forwarded_answers_go_back_under_the_clients_id() {
+    let fake = FakeUpstream::new(ResultCode::NOERROR);
+    let client = udp_client(Server::new(Config::with(|c| {
+      c.upstreams = vec![upstream(&fake)]
+    })));
+    for _ in 0..2 {
+      client
+        .send(&query(0x4242, "www.example.test", QueryType::A))
+        .unwrap();
+      let answer = receive(&client).unwrap();
+      assert_eq!(answer.tx_id, 0x4242);
+      assert_eq!(answer.flags.rcode, ResultCode::NOERROR);
+      assert!(answer.flags.recursion_available);
+      assert!(!answer.flags.authoritative);
+      assert_eq!(
+        answer.answers,
+        vec![DnsRecord::A {
+          domain: "www.example.test".into(),
+          addr: Ipv4Addr::new(192, 0, 2, 1),
+          ttl: 300,
+        }]
+      );
+    }
+    // the second one came out of the cache
+    assert_eq!(fake.queries(), 1);
+  }
+
+  #[test]
+  fn only_forwards_wait_on_the_udp_workers() {
+    let text = format!("$TTL 60\n{}www A 10.0.0.1\n", SOA);
+    let fixture = Fixture::new("udp-workers", &[("db", &text)]);
+    let fake = FakeUpstream::start(ResultCode::NOERROR, Duration::from_millis(300), 0);
+    let client = udp_client(Server::new(Config::with(|c| {
+      c.upstreams = vec![upstream(&fake)];
+      c.zones.push(fixture.zone_file("local.test", "db"));
+      c.udp_workers = 1;
+      c.udp_max_queries = 1;
+    })));
+
+    // the one worker takes the first, the second waits for it, and there's no
+    // room left for the third
+    let started = Instant::now();
+    client
+      .send(&query(1, "a.example.test", QueryType::A))
+      .unwrap();
+    thread::sleep(Duration::from_millis(50));
+    client
+      .send(&query(2, "b.example.test", QueryType::A))
+      .unwrap();
+    client
+      .send(&query(3, "c.example.test", QueryType::A))
+      .unwrap();
+    // none of which holds up what we can answer ourselves
+    client
+      .send(&query(4, "www.local.test", QueryType::A))
+      .unwrap();
+    let local = receive(&client).unwrap();
+    assert_eq!(local.tx_id, 4);
+    assert!(local.flags.authoritative);
+    assert!(started.elapsed() < Duration::from_millis(300));
+
+    // the third is never coming, so don't wait on it for too long
+    client
+      .set_read_timeout(Some(Duration::from_secs(1)))
+      .unwrap();
+
+    let forwarded: BTreeSet<u16> = (0..3)
+      .map_while(|_| receive(&client))
+      .map(|m| m.tx_id)
+      .collect();
+    assert_eq!(forwarded, BTreeSet::from([1, 2]));
+    assert_eq!(fake.queries(), 2);
+  }
+
+  #[test]
+  fn udp_answers_go_back_to_whoever_asked() {
+    let first = udp_client(Server::new(Config::default().unwrap()));
+    let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+    second.connect(first.peer_addr().unwrap()).unwrap();
+    second
+      .set_read_timeout(Some(Duration::from_secs(2)))
+      .unwrap();
+
+    first
+      .send(&query(1, "one.example.test", QueryType::A))
+      .unwrap();
+    second
+      .send(&query(2, "two.example.test", QueryType::AAAA))
+      .unwrap();
+    for (client, tx_id, question) in [
+      (
+        &first,
+        1,
+        DnsQuestion::new("one.example.test", QueryType::A),
+      ),
+      (
+        &second,
+        2,
+        DnsQuestion::new("two.example.test", QueryType::AAAA),
+      ),
+    ] {
+      let answer = receive(client).unwrap();
+      assert_eq!(answer.tx_id, tx_id);
+      assert_eq!(answer.flags.rq, DnsMessageType::Response);
+      assert!(answer.flags.recursive);
+      assert_eq!(answer.questions, vec![question]);
+    }
+  }
+
+  #[test]
+  fn misses_on_the_same_name_share_one_upstream_lookup() {
+    let fake = FakeUpstream::start(ResultCode::NOERROR, Duration::from_millis(300), 0);
+    let server = Arc::new(Server::new(Config::with(|c| {
+      c.upstreams = vec![upstream(&fake)]
+    })));
+    let askers: Vec<_> = (1..=5)
+      .map(|tx_id| {
+        let server = server.clone();
+        thread::spawn(move || {
+          let client = SockAddr::from("127.0.0.1:5353".parse::<SocketAddr>().unwrap());
+          let query = query(tx_id, "www.example.test", QueryType::A);
+          parse(&handle_query(&server, &query, &client, Transport::Udp).unwrap())
+        })
+      })
+      .collect();
+    for (tx_id, asker) in (1..=5).zip(askers) {
+      let answer = asker.join().unwrap();
+      assert_eq!(answer.tx_id, tx_id);
+      assert_eq!(answer.flags.rcode, ResultCode::NOERROR);
+      assert_eq!(answer.answers.len(), 1);
+    }
+    assert_eq!(fake.queries(), 1);
   }
-  #[allow(unreachable_code)]
-  Ok(())
 }
@@ -0,0 +1,111 @@
+use crate::{
+  dnsmessage::{DnsMessage, DnsMessageType, DnsRecord, QueryType, ResultCode},
+  zone::ZoneFile,
+};
+use std::{
+  env, fs,
+  net::{Ipv4Addr, SocketAddr, UdpSocket},
+  path::PathBuf,
+  process,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+  thread,
+  time::Duration,
+};
+
+/// Files in a scratch directory of their own, which goes away again with the
+/// fixture
+pub(crate) struct Fixture {
+  dir: PathBuf,
+}
+
+impl Fixture {
+  pub(crate) fn new(name: &str, files: &[(&str, &str)]) -> Fixture {
+    let dir = env::temp_dir().join(format!("dinosaurus-zone-{}-{}", name, process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    for (file, text) in files {
+      fs::write(dir.join(file), text).unwrap();
+    }
+    Fixture { dir }
+  }
+
+  /// `file` from the fixture as a zone for `origin`
+  pub(crate) fn zone_file(&self, origin: &str, file: &str) -> ZoneFile {
+    ZoneFile {
+      origin: origin.to_string(),
+      path: self.dir.join(file),
+    }
+  }
+}
+
+impl Drop for Fixture {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.dir);
+  }
+}
+
+/// The SOA every test zone needs, owned by the zone's origin
+pub(crate) const SOA: &str = "@ IN SOA ns1 hostmaster 1 7200 3600 1209600 300\n";
+
+/// An upstream on localhost that answers every query with `rcode`, and for
+/// successful A queries an address, keeping count of the queries it's had
+pub(crate) struct FakeUpstream {
+  pub(crate) addr: SocketAddr,
+  queries: Arc<AtomicUsize>,
+}
+
+impl FakeUpstream {
+  pub(crate) fn new(rcode: ResultCode) -> FakeUpstream {
+    FakeUpstream::start(rcode, Duration::ZERO, 0)
+  }
+
+  /// One that waits `delay` before each answer, and never answers the first
+  /// `dropped` queries at all
+  pub(crate) fn start(rcode: ResultCode, delay: Duration, dropped: usize) -> FakeUpstream {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let queries = Arc::new(AtomicUsize::new(0));
+    let count = queries.clone();
+    thread::spawn(move || {
+      let mut buf = [0u8; 512];
+      while let Ok((len, client)) = socket.recv_from(&mut buf) {
+        let mut query = DnsMessage::default();
+        let query = match query.parse(&buf[..len]) {
+          Ok(q) => q,
+          Err(_) => continue,
+        };
+        if count.fetch_add(1, Ordering::SeqCst) < dropped {
+          continue;
+        }
+        thread::sleep(delay);
+        let mut response = DnsMessage {
+          tx_id: query.tx_id,
+          questions: query.questions.clone(),
+          ..DnsMessage::default()
+        };
+        response.flags.rq = DnsMessageType::Response;
+        response.flags.rcode = rcode;
+        if rcode == ResultCode::NOERROR {
+          response.answers = query
+            .questions
+            .iter()
+            .filter(|q| q.qtype == QueryType::A)
+            .map(|q| DnsRecord::A {
+              domain: q.name.clone(),
+              addr: Ipv4Addr::new(192, 0, 2, 1),
+              ttl: 300,
+            })
+            .collect();
+        }
+        let _ = socket.send_to(&response.to_wire(512).unwrap(), client);
+      }
+    });
+    FakeUpstream { addr, queries }
+  }
+
+  pub(crate) fn queries(&self) -> usize {
+    self.queries.load(Ordering::SeqCst)
+  }
+}
@@ -0,0 +1,530 @@
+use crate::{
+  config::Config,
+  dnserror::DnsError,
+  dnsmessage::{DnsMessage, DnsMessageType, DnsQuestion, Edns, Flags, QueryType, ResultCode},
+};
+use std::{
+  collections::hash_map::RandomState,
+  hash::{BuildHasher, Hasher},
+  io::{ErrorKind, Read, Write},
+  net::{SocketAddr, TcpStream, UdpSocket},
+  str::FromStr,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+  },
+  time::{Duration, Instant},
+};
+
+/// A resolver we can forward to, and how long to wait on it if it's not the
+/// configured default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Upstream {
+  pub addr: SocketAddr,
+  pub timeout: Option<Duration>,
+}
+
+/// Queries for names under `suffix` go to `upstreams` instead of the defaults
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ForwardRule {
+  pub suffix: String,
+  pub upstreams: Vec<Upstream>,
+}
+
+/// Which upstream we try first for each query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Strategy {
+  // always in the order they're configured, moving down the list on failure
+  Failover,
+  // each query starts one further along the list than the last
+  RoundRobin,
+  Random,
+  // lowest smoothed round trip time first
+  Fastest,
+}
+
+impl FromStr for Strategy {
+  type Err = DnsError;
+
+  fn from_str(s: &str) -> Result<Strategy, DnsError> {
+    match s {
+      "failover" => Ok(Strategy::Failover),
+      "round_robin" => Ok(Strategy::RoundRobin),
+      "random" => Ok(Strategy::Random),
+      "fastest" => Ok(Strategy::Fastest),
+      _ => Err(
+        format!(
+          "unknown upstream strategy {:?}, expected failover, round_robin, random or fastest",
+          s
+        )
+        .as_str()
+        .into(),
+      ),
+    }
+  }
+}
+
+/// What we've learned about an upstream from talking to it
+#[derive(Debug, Default)]
+struct Health {
+  consecutive_failures: u32,
+  // set once we've given up on it; cleared by the next answer it gives us
+  down_since: Option<Instant>,
+  // smoothed round trip time, as tcp does it: srtt = 7/8 srtt + 1/8 sample
+  srtt: Option<Duration>,
+}
+
+/// Relays queries we can't answer ourselves to a pool of other resolvers, keeping
+/// track of which of them are answering and how quickly.
+#[derive(Debug)]
+pub(crate) struct Forwarder {
+  upstreams: Vec<Upstream>,
+  strategy: Strategy,
+  timeout: Duration,
+  attempts: usize,
+  max_failures: u32,
+  health: Mutex<Vec<Health>>,
+  next: AtomicUsize,
+}
+
+impl Forwarder {
+  /// A pool of `upstreams`, run with the strategy and limits from the config
+  pub fn new(upstreams: Vec<Upstream>, c: &Config) -> Forwarder {
+    Forwarder {
+      health: Mutex::new(upstreams.iter().map(|_| Health::default()).collect()),
+      upstreams,
+      strategy: c.upstream_strategy,
+      timeout: Duration::from_millis(c.upstream_timeout),
+      attempts: c.upstream_attempts.max(1),
+      max_failures: c.upstream_max_failures.max(1),
+      next: AtomicUsize::new(0),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.upstreams.is_empty()
+  }
+
+  /// Ask upstreams in the order our strategy picks until one of them gives us an
+  /// answer. Each gets `upstream_attempts` tries before we move on to the next, but
+  /// only counts one failure towards being marked down however many of them it
+  /// misses, so a single unlucky query can't take an upstream out by itself.
+  /// Upstreams that are down go to the back of the line, but are still tried if
+  /// nobody else is left. A SERVFAIL, REFUSED or NOTIMP isn't an answer, just an
+  /// upstream that couldn't help, and asking it again won't change its mind.
+  pub fn forward(&self, query: &DnsMessage) -> Result<DnsMessage, DnsError> {
+    let mut last_error: DnsError = "no upstreams configured".into();
+    for i in self.candidates() {
+      let upstream = &self.upstreams[i];
+      for _ in 0..self.attempts {
+        let started = Instant::now();
+        match exchange(query, upstream.addr, self.timeout_for(upstream)) {
+          Ok(response)
+            if matches!(
+              response.flags.rcode,
+              ResultCode::SERVFAIL | ResultCode::REFUSED | ResultCode::NOTIMP
+            ) =>
+          {
+            eprintln!(
+              "upstream {} answered {:?}",
+              upstream.addr, response.flags.rcode
+            );
+            last_error = format!("upstream answered {:?}", response.flags.rcode)
+              .as_str()
+              .into();
+            break;
+          }
+          Ok(response) => {
+            self.record_success(i, started.elapsed());
+            return Ok(response);
+          }
+          Err(e) => {
+            eprintln!("upstream {} failed: {}", upstream.addr, e);
+            last_error = e;
+          }
+        }
+      }
+      self.record_failure(i);
+    }
+    Err(last_error)
+  }
+
+  /// Check in on every upstream that's marked down, bringing back any that answer.
+  /// Anything counts as an answer, even a refusal; we only want to know it's there.
+  pub fn probe_down(&self) {
+    let down: Vec<usize> = {
+      let health = self.health.lock().unwrap();
+      (0..self.upstreams.len())
+        .filter(|&i| health[i].down_since.is_some())
+        .collect()
+    };
+    let probe = DnsMessage {
+      questions: vec![DnsQuestion {
+        name: String::new(),
+        qtype: QueryType::NS,
+        class: 1,
+      }],
+      ..DnsMessage::default()
+    };
+    for i in down {
+      let upstream = &self.upstreams[i];
+      let started = Instant::now();
+      match exchange(&probe, upstream.addr, self.timeout_for(upstream)) {
+        Ok(_) => {
+          eprintln!("upstream {} is answering again", upstream.addr);
+          self.record_success(i, started.elapsed());
+        }
+        Err(e) => eprintln!("upstream {} is still down: {}", upstream.addr, e),
+      }
+    }
+  }
+
+  /// The longest `forward` spends waiting on upstreams that never answer
+  pub fn max_wait(&self) -> Duration {
+    self
+      .upstreams
+      .iter()
+      .map(|u| self.timeout_for(u) * self.attempts as u32)
+      .sum()
+  }
+
+  fn timeout_for(&self, upstream: &Upstream) -> Duration {
+    upstream.timeout.unwrap_or(self.timeout)
+  }
+
+  /// The order to try upstreams in for the next query
+  fn candidates(&self) -> Vec<usize> {
+    let health = self.health.lock().unwrap();
+    let mut order: Vec<usize> = (0..self.upstreams.len()).collect();
+    if order.is_empty() {
+      return order;
+    }
+    match self.strategy {
+      Strategy::Failover => {}
+      Strategy::RoundRobin => {
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % order.len();
+        order.rotate_left(start);
+      }
+      Strategy::Random => {
+        // fisher-yates
+        for i in (1..order.len()).rev() {
+          order.swap(i, (random() % (i as u64 + 1)) as usize);
+        }
+      }
+      Strategy::Fastest => {
+        // ones we haven't timed yet sort first, so they get measured
+        order.sort_by_key(|&i| health[i].srtt.unwrap_or(Duration::ZERO));
+      }
+    }
+    // a stable sort, so the healthy ones keep the order the strategy gave them
+    order.sort_by_key(|&i| health[i].down_since.is_some());
+    order
+  }
+
+  fn record_success(&self, i: usize, rtt: Duration) {
+    let mut health = self.health.lock().unwrap();
+    let h = &mut health[i];
+    h.consecutive_failures = 0;
+    h.down_since = None;
+    h.srtt = Some(match h.srtt {
+      Some(srtt) => (srtt * 7 + rtt) / 8,
+      None => rtt,
+    });
+  }
+
+  fn record_failure(&self, i: usize) {
+    let timeout = self.timeout_for(&self.upstreams[i]);
+    let mut health = self.health.lock().unwrap();
+    let h = &mut health[i];
+    h.consecutive_failures += 1;
+    // a timeout is at least as slow as the timeout, so fastest stops favouring it
+    h.srtt = Some(h.srtt.map_or(timeout, |srtt| (srtt * 7 + timeout) / 8));
+    if h.consecutive_failures >= self.max_failures && h.down_since.is_none() {
+      eprintln!(
+        "marking upstream {} down after {} failures in a row",
+        self.upstreams[i].addr, h.consecutive_failures
+      );
+      h.down_since = Some(Instant::now());
+    }
+  }
+}
+
+/// Send the question(s) from `query` to one upstream and wait for its answer.
+///
+/// We go over udp first, and retry over tcp if the answer comes back with TC set.
+/// The upstream sees its own transaction id, never the client's; putting the
+/// client's back is up to whoever relays the answer.
+pub(crate) fn exchange(
+  query: &DnsMessage,
+  upstream: SocketAddr,
+  timeout: Duration,
+) -> Result<DnsMessage, DnsError> {
+  let request = upstream_query(query);
+  let bytes = request.to_wire(u16::MAX as usize)?;
+  let response = exchange_udp(&bytes, &request, upstream, timeout)?;
+  if response.flags.truncated {
+    eprintln!(
+      "upstream {} truncated its answer, retrying over tcp",
+      upstream
+    );
+    return exchange_tcp(&bytes, &request, upstream, timeout);
+  }
+  Ok(response)
+}
+
+/// A fresh query carrying the client's question, but with an id of our own picking
+/// so answers can't be spoofed by anyone who saw the client's query go by.
+fn upstream_query(query: &DnsMessage) -> DnsMessage {
+  DnsMessage {
+    tx_id: random_id(),
+    flags: Flags {
+      recursive: true,
+      checking_disabled: query.flags.checking_disabled,
+      ..Flags::default()
+    },
+    questions: query.questions.clone(),
+    edns: Some(Edns {
+      dnssec_ok: query.edns.as_ref().map(|e| e.dnssec_ok).unwrap_or(false),
+      ..Edns::default()
+    }),
+    ..DnsMessage::default()
+  }
+}
+
+/// Only take an answer that's to the question we actually asked
+fn matches(request: &DnsMessage, response: &DnsMessage) -> bool {
+  response.tx_id == request.tx_id
+    && response.flags.rq == DnsMessageType::Response
+    && response.questions == request.questions
+}
+
+fn exchange_udp(
+  bytes: &[u8],
+  request: &DnsMessage,
+  upstream: SocketAddr,
+  timeout: Duration,
+) -> Result<DnsMessage, DnsError> {
+  let local = if upstream.is_ipv4() {
+    "0.0.0.0:0"
+  } else {
+    "[::]:0"
+  };
+  let socket = UdpSocket::bind(local)?;
+  // connecting means the kernel drops anything that isn't from the upstream
+  socket.connect(upstream)?;
+  socket.send(bytes)?;
+
+  let deadline = Instant::now() + timeout;
+  let mut buf = vec![0u8; u16::MAX as usize];
+  loop {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+      return Err(DnsError::Io(ErrorKind::TimedOut.into()));
+    }
+    socket.set_read_timeout(Some(remaining))?;
+    let len = socket.recv(&mut buf)?;
+    let mut response = DnsMessage::default();
+    match response.parse(&buf[..len]) {
+      Ok(r) if matches(request, r) => return Ok(response),
+      Ok(r) => eprintln!(
+        "ignoring mismatched answer {:02x} from {}",
+        r.tx_id, upstream
+      ),
+      Err(e) => eprintln!("ignoring garbled answer from {}: {}", upstream, e),
+    }
+  }
+}
+
+fn exchange_tcp(
+  bytes: &[u8],
+  request: &DnsMessage,
+  upstream: SocketAddr,
+  timeout: Duration,
+) -> Result<DnsMessage, DnsError> {
+  let mut stream = TcpStream::connect_timeout(&upstream, timeout)?;
+  stream.set_read_timeout(Some(timeout))?;
+  stream.set_write_timeout(Some(timeout))?;
+
+  let mut framed = Vec::with_capacity(bytes.len() + 2);
+  framed.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+  framed.extend_from_slice(bytes);
+  stream.write_all(&framed)?;
+
+  let mut len_buf = [0u8; 2];
+  stream.read_exact(&mut len_buf)?;
+  let mut buf = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+  stream.read_exact(&mut buf)?;
+
+  let mut response = DnsMessage::default();
+  response.parse(&buf)?;
+  if !matches(request, &response) {
+    return Err("upstream answered a different question over tcp".into());
+  }
+  Ok(response)
+}
+
+/// A random number, without pulling in a crate. std seeds RandomState's keys from
+/// the os once per thread and bumps one of them for every new RandomState, so each
+/// call hashes with different keys; nobody who doesn't know those keys can guess
+/// the output, which is plenty for picking ids and upstreams.
+fn random() -> u64 {
+  RandomState::new().build_hasher().finish()
+}
+
+fn random_id() -> u16 {
+  random() as u16
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::FakeUpstream;
+
+  fn upstreams(addrs: &[&str]) -> Vec<Upstream> {
+    addrs
+      .iter()
+      .map(|u| Upstream {
+        addr: u.parse().unwrap(),
+        timeout: None,
+      })
+      .collect()
+  }
+
+  #[test]
+  fn failover_keeps_the_configured_order() {
+    let f = Forwarder::new(
+      upstreams(&["10.0.0.1:53", "10.0.0.2:53", "10.0.0.3:53"]),
+      &Config::with(|c| c.upstream_strategy = Strategy::Failover),
+    );
+    assert_eq!(f.candidates(), vec![0, 1, 2]);
+    assert_eq!(f.candidates(), vec![0, 1, 2]);
+  }
+
+  #[test]
+  fn round_robin_starts_one_further_along_each_time() {
+    let f = Forwarder::new(
+      upstreams(&["10.0.0.1:53", "10.0.0.2:53", "10.0.0.3:53"]),
+      &Config::with(|c| c.upstream_strategy = Strategy::RoundRobin),
+    );
+    assert_eq!(f.candidates(), vec![0, 1, 2]);
+    assert_eq!(f.candidates(), vec![1, 2, 0]);
+    assert_eq!(f.candidates(), vec![2, 0, 1]);
+    assert_eq!(f.candidates(), vec![0, 1, 2]);
+  }
+
+  #[test]
+  fn fastest_goes_by_round_trip_time() {
+    let f = Forwarder::new(
+      upstreams(&["10.0.0.1:53", "10.0.0.2:53", "10.0.0.3:53"]),
+      &Config::with(|c| c.upstream_strategy = Strategy::Fastest),
+    );
+    f.record_success(0, Duration::from_millis(80));
+    f.record_success(1, Duration::from_millis(20));
+    // 2 hasn't been timed yet, so it goes first to get measured
+    assert_eq!(f.candidates(), vec![2, 1, 0]);
+  }
+
+  #[test]
+  fn failing_upstreams_go_to_the_back_until_they_answer() {
+    let f = Forwarder::new(
+      upstreams(&["10.0.0.1:53", "10.0.0.2:53", "10.0.0.3:53"]),
+      &Config::with(|c| {
+        c.upstream_strategy = Strategy::Failover;
+        c.upstream_max_failures = 2;
+      }),
+    );
+    f.record_failure(0);
+    assert_eq!(f.candidates(), vec![0, 1, 2]);
+    f.record_failure(0);
+    assert_eq!(f.candidates(), vec![1, 2, 0]);
+    f.record_failure(1);
+    f.record_failure(1);
+    assert_eq!(f.candidates(), vec![2, 0, 1]);
+
+    // a success resets the count, so it takes max_failures in a row again
+    f.record_success(0, Duration::from_millis(10));
+    assert_eq!(f.candidates(), vec![0, 2, 1]);
+    f.record_failure(0);
+    assert_eq!(f.candidates(), vec![0, 2, 1]);
+  }
+
+  #[test]
+  fn refusals_move_on_to_the_next_upstream() {
+    let refusing = FakeUpstream::new(ResultCode::REFUSED).addr;
+    let failing = FakeUpstream::new(ResultCode::SERVFAIL).addr;
+    let working = FakeUpstream::new(ResultCode::NXDOMAIN).addr;
+    let f = Forwarder::new(
+      upstreams(&[
+        &refusing.to_string(),
+        &failing.to_string(),
+        &working.to_string(),
+      ]),
+      &Config::with(|c| {
+        c.upstream_strategy = Strategy::Failover;
+        c.upstream_attempts = 3;
+        c.upstream_max_failures = 1;
+        c.upstream_timeout = 2000;
+      }),
+    );
+    let query = DnsMessage {
+      questions: vec![DnsQuestion::new("www.example.test", QueryType::A)],
+      ..DnsMessage::default()
+    };
+    let answer = f.forward(&query).unwrap();
+    assert_eq!(answer.flags.rcode, ResultCode::NXDOMAIN);
+    // and the two that didn't help are down now
+    assert_eq!(f.candidates(), vec![2, 0, 1]);
+  }
+
+  #[test]
+  fn each_upstream_gets_its_own_tries() {
+    let lossy = FakeUpstream::start(ResultCode::NOERROR, Duration::ZERO, 1);
+    let dead = FakeUpstream::start(ResultCode::NOERROR, Duration::ZERO, usize::MAX);
+    let working = FakeUpstream::new(ResultCode::NOERROR);
+    let f = Forwarder::new(
+      [&lossy, &dead, &working]
+        .iter()
+        .map(|u| Upstream {
+          addr: u.addr,
+          timeout: Some(Duration::from_millis(200)),
+        })
+        .collect(),
+      &Config::with(|c| {
+        c.upstream_strategy = Strategy::Failover;
+        c.upstream_attempts = 2;
+        c.upstream_max_failures = 1;
+      }),
+    );
+    let query = DnsMessage {
+      questions: vec![DnsQuestion::new("www.example.test", QueryType::A)],
+      ..DnsMessage::default()
+    };
+
+    // the first try is lost, the second gets through, and one lost packet isn't
+    // enough to call it down
+    assert_eq!(f.forward(&query).unwrap().answers.len(), 1);
+    assert_eq!(lossy.queries(), 2);
+    assert_eq!(f.candidates(), vec![0, 1, 2]);
+
+    // once the first is down, the next gets all its tries before the last one
+    f.record_failure(0);
+    assert_eq!(f.forward(&query).unwrap().answers.len(), 1);
+    assert_eq!(dead.queries(), 2);
+    assert_eq!(working.queries(), 1);
+    assert_eq!(f.candidates(), vec![2, 0, 1]);
+  }
+
+  #[test]
+  fn max_wait_covers_every_try_at_every_upstream() {
+    let mut pool = upstreams(&["10.0.0.1:53", "10.0.0.2:53"]);
+    pool[1].timeout = Some(Duration::from_millis(200));
+    let f = Forwarder::new(
+      pool,
+      &Config::with(|c| {
+        c.upstream_timeout = 2000;
+        c.upstream_attempts = 2;
+      }),
+    );
+    assert_eq!(f.max_wait(), Duration::from_millis(4400));
+  }
+}
@@ -0,0 +1,240 @@
+use crate::{
+  blocklist::Blocklist,
+  config::Config,
+  dnserror::DnsError,
+  dnsmessage::{in_zone, DnsMessage, DnsQuestion},
+  upstream::{ForwardRule, Forwarder},
+  zone::{Zone, ZoneFile},
+};
+use std::{net::IpAddr, path::PathBuf, str::FromStr};
+
+/// A block of addresses, like `192.168.0.0/16` or `fd00::/8`. A bare address is
+/// a network of just that one address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Network {
+  addr: IpAddr,
+  prefix: u8,
+}
+
+impl Network {
+  pub fn contains(&self, addr: IpAddr) -> bool {
+    // a v4 client on a v6 socket shows up as ::ffff:a.b.c.d
+    let addr = match addr {
+      IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+      v4 => v4,
+    };
+    match (self.addr, addr) {
+      (IpAddr::V4(net), IpAddr::V4(a)) => prefix_matches(&net.octets(), &a.octets(), self.prefix),
+      (IpAddr::V6(net), IpAddr::V6(a)) => prefix_matches(&net.octets(), &a.octets(), self.prefix),
+      _ => false,
+    }
+  }
+}
+
+/// Whether the first `prefix` bits of `a` and `b` are the same
+fn prefix_matches(a: &[u8], b: &[u8], prefix: u8) -> bool {
+  let full = (prefix / 8) as usize;
+  let rest = prefix % 8;
+  if a[..full] != b[..full] {
+    return false;
+  }
+  rest == 0 || (a[full] ^ b[full]) >> (8 - rest) == 0
+}
+
+impl FromStr for Network {
+  type Err = DnsError;
+
+  fn from_str(s: &str) -> Result<Network, DnsError> {
+    let bad = || DnsError::from(format!("bad network {:?}", s).as_str());
+    let (addr, prefix) = match s.split_once('/') {
+      Some((addr, prefix)) => (addr, Some(prefix)),
+      None => (s, None),
+    };
+    let addr = addr.parse::<IpAddr>().map_err(|_| bad())?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+      Some(p) => p.parse::<u8>().map_err(|_| bad())?,
+      None => max,
+    };
+    if prefix > max {
+      return Err(bad());
+    }
+    Ok(Network { addr, prefix })
+  }
+}
+
+/// A view as given in the config: who it's for, and what they get to see
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ViewConfig {
+  pub name: String,
+  pub networks: Vec<Network>,
+  pub zones: Vec<ZoneFile>,
+  pub forward_rules: Vec<ForwardRule>,
+  // names blocked for this view, on top of the global blocklists
+  pub blocklists: Vec<PathBuf>,
+  // whether questions we can't answer from local data get forwarded, or REFUSED
+  pub recursion: bool,
+}
+
+impl ViewConfig {
+  pub fn new(name: String, networks: Vec<Network>) -> ViewConfig {
+    ViewConfig {
+      name,
+      networks,
+      zones: Vec::new(),
+      forward_rules: Vec::new(),
+      blocklists: Vec::new(),
+      recursion: true,
+    }
+  }
+}
+
+/// What one group of clients sees (split horizon). A view's own zones, forward
+/// rules and blocklists are tried before the global ones, so the same name can mean
+/// something different inside the view than outside it.
+#[derive(Debug)]
+pub(crate) struct View {
+  pub name: String,
+  networks: Vec<Network>,
+  // longest origin first
+  pub zones: Vec<Zone>,
+  // longest suffix first
+  pub forward_rules: Vec<(String, Forwarder)>,
+  blocklist: Blocklist,
+  pub recursion: bool,
+}
+
+impl View {
+  pub fn new(view: &ViewConfig, config: &Config) -> View {
+    View {
+      name: view.name.clone(),
+      networks: view.networks.clone(),
+      zones: load_zones(&view.zones),
+      forward_rules: forward_rules(&view.forward_rules, config),
+      blocklist: Blocklist::load(&view.blocklists, config.block_action, config.block_ttl),
+      recursion: view.recursion,
+    }
+  }
+
+  /// Whether this view is for `client`
+  pub fn matches(&self, client: IpAddr) -> bool {
+    self.networks.iter().any(|n| n.contains(client))
+  }
+
+  /// The most specific of this view's zones that `name` falls in
+  pub fn zone_for(&self, name: &str) -> Option<&Zone> {
+    self.zones.iter().find(|z| in_zone(name, &z.origin))
+  }
+
+  /// The forwarder for this view's longest suffix rule matching `name`
+  pub fn forwarder_for(&self, name: &str) -> Option<&Forwarder> {
+    self
+      .forward_rules
+      .iter()
+      .find(|(suffix, _)| in_zone(name, suffix))
+      .map(|(_, f)| f)
+  }
+
+  /// Our answer to `question` if this view's blocklists have its name
+  pub fn blocked(&self, question: &DnsQuestion) -> Option<DnsMessage> {
+    self.blocklist.lookup(question)
+  }
+}
+
+/// Load each zone, skipping (and complaining about) any that won't load, and put
+/// them longest origin first so the first match is the most specific
+pub(crate) fn load_zones(files: &[ZoneFile]) -> Vec<Zone> {
+  let mut zones: Vec<Zone> = files
+    .iter()
+    .filter_map(|z| match Zone::load(z) {
+      Ok(zone) => {
+        eprintln!("loaded zone {}. from {:?}", zone.origin, z.path);
+        Some(zone)
+      }
+      Err(e) => {
+        eprintln!("couldn't load zone {}.: {}", z.origin, e);
+        None
+      }
+    })
+    .collect();
+  zones.sort_by_key(|z| std::cmp::Reverse(z.origin.len()));
+  zones
+}
+
+/// A forwarder for each rule, longest suffix first so the first match is the best
+pub(crate) fn forward_rules(rules: &[ForwardRule], config: &Config) -> Vec<(String, Forwarder)> {
+  let mut rules: Vec<(String, Forwarder)> = rules
+    .iter()
+    .map(|r| {
+      (
+        r.suffix.clone(),
+        Forwarder::new(r.upstreams.clone(), config),
+      )
+    })
+    .collect();
+  rules.sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+  rules
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn contains(network: &str, addr: &str) -> bool {
+    network
+      .parse::<Network>()
+      .unwrap()
+      .contains(addr.parse().unwrap())
+  }
+
+  #[test]
+  fn networks_parse() {
+    assert_eq!(
+      "192.168.0.0/16".parse::<Network>().unwrap(),
+      Network {
+        addr: "192.168.0.0".parse().unwrap(),
+        prefix: 16,
+      }
+    );
+    // a bare address is a network of one
+    assert_eq!("10.0.0.1".parse::<Network>().unwrap().prefix, 32);
+    assert_eq!("fd00::1".parse::<Network>().unwrap().prefix, 128);
+    assert_eq!("::/0".parse::<Network>().unwrap().prefix, 0);
+    for bad in [
+      "",
+      "10.0.0.0/33",
+      "fd00::/129",
+      "10.0.0.0/",
+      "10.0.0.0/x",
+      "lan",
+    ] {
+      assert!(bad.parse::<Network>().is_err(), "{:?}", bad);
+    }
+  }
+
+  #[test]
+  fn prefixes_match_bit_by_bit() {
+    assert!(contains("192.168.0.0/16", "192.168.200.7"));
+    assert!(!contains("192.168.0.0/16", "192.169.0.1"));
+    // prefixes that don't fall on a byte boundary
+    assert!(contains("10.0.0.0/9", "10.127.255.255"));
+    assert!(!contains("10.0.0.0/9", "10.128.0.0"));
+    assert!(contains("172.16.0.0/12", "172.31.1.1"));
+    assert!(!contains("172.16.0.0/12", "172.32.0.1"));
+    assert!(contains("10.0.0.1", "10.0.0.1"));
+    assert!(!contains("10.0.0.1", "10.0.0.2"));
+    assert!(contains("0.0.0.0/0", "203.0.113.9"));
+    assert!(contains("fd00::/8", "fdab::1"));
+    assert!(!contains("fd00::/8", "fe80::1"));
+    assert!(contains("2001:db8::/33", "2001:db8:7fff::1"));
+    assert!(!contains("2001:db8::/33", "2001:db8:8000::1"));
+  }
+
+  #[test]
+  fn families_dont_mix_except_for_mapped_addresses() {
+    assert!(!contains("0.0.0.0/0", "::1"));
+    assert!(!contains("::/0", "127.0.0.1"));
+    // a v4 client on a v6 socket
+    assert!(contains("192.168.0.0/16", "::ffff:192.168.1.1"));
+  }
+}
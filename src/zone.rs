@@ -0,0 +1,891 @@
+use crate::{
+  dnserror::DnsError,
+  dnsmessage::{
+    in_zone, normalize_name, DnsMessage, DnsQuestion, DnsRecord, Flags, QueryType, ResultCode,
+  },
+};
+use std::{
+  collections::{HashMap, HashSet},
+  fs, mem,
+  net::{Ipv4Addr, Ipv6Addr},
+  path::{Path, PathBuf},
+};
+
+/// How deep $INCLUDEs can nest before we decide they must be including each other
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// A zone to load, as given in the config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ZoneFile {
+  pub origin: String,
+  pub path: PathBuf,
+}
+
+/// A zone we're authoritative for
+#[derive(Debug)]
+pub(crate) struct Zone {
+  pub origin: String,
+  soa: DnsRecord,
+  // every record in the zone, by owner name
+  records: HashMap<String, Vec<DnsRecord>>,
+  // every name that exists: the owners above, and the names between them and the
+  // origin, which exist even though they own nothing (empty non-terminals)
+  names: HashSet<String>,
+}
+
+impl Zone {
+  /// Read a zone from an RFC 1035 master file. The zone has to have exactly one SOA,
+  /// at its origin; records for names outside the zone are skipped.
+  pub fn load(file: &ZoneFile) -> Result<Zone, DnsError> {
+    let origin = normalize_name(&file.origin);
+    let mut parser = Parser::default();
+    parser.parse_file(
+      &file.path,
+      Context {
+        origin: origin.clone(),
+        ..Context::default()
+      },
+      0,
+    )?;
+
+    let mut soa = None;
+    let mut records: HashMap<String, Vec<DnsRecord>> = HashMap::new();
+    let mut names = HashSet::new();
+    for r in parser.records {
+      let owner = r.domain().to_string();
+      if !in_zone(&owner, &origin) {
+        eprintln!(
+          "{}: skipping {}, it's not in {}.",
+          file.path.display(),
+          owner,
+          origin
+        );
+        continue;
+      }
+      if let DnsRecord::SOA { .. } = r {
+        if owner != origin {
+          eprintln!(
+            "{}: skipping SOA for {}, only {}. gets one",
+            file.path.display(),
+            owner,
+            origin
+          );
+          continue;
+        }
+        if soa.is_some() {
+          return Err(
+            format!("{}: more than one SOA", file.path.display())
+              .as_str()
+              .into(),
+          );
+        }
+        soa = Some(r.clone());
+      }
+
+      let mut name = owner.clone();
+      while names.insert(name.clone()) && name != origin {
+        name = match name.split_once('.') {
+          Some((_, parent)) => parent.to_string(),
+          None => String::new(),
+        };
+      }
+      records.entry(owner).or_default().push(r);
+    }
+
+    match soa {
+      Some(soa) => Ok(Zone {
+        origin,
+        soa,
+        records,
+        names,
+      }),
+      None => Err(
+        format!("{}: no SOA for {}.", file.path.display(), origin)
+          .as_str()
+          .into(),
+      ),
+    }
+  }
+
+  /// Our authoritative answer to `question`, or None if it's not in this zone.
+  ///
+  /// Names that don't exist can still be answered by a wildcard. Names that exist
+  /// but have nothing of the asked-for type get NODATA, and names that don't exist
+  /// at all get NXDOMAIN; both come with our SOA in the authority section so they
+  /// can be cached (RFC 2308).
+  ///
+  /// CNAMEs are handed back as they are; following them is up to the caller.
+  pub fn lookup(&self, question: &DnsQuestion) -> Option<DnsMessage> {
+    if question.class != 1 || !in_zone(&question.name, &self.origin) {
+      return None;
+    }
+    let mut answer = DnsMessage {
+      flags: Flags {
+        authoritative: true,
+        ..Flags::default()
+      },
+      ..DnsMessage::default()
+    };
+    match self.records.get(&question.name) {
+      Some(rrs) => answer.answers = matching(rrs, question),
+      None if self.names.contains(&question.name) => {}
+      None => match self.wildcard(&question.name) {
+        // the wildcard's records, made out as if they were the name's own
+        Some(rrs) => {
+          answer.answers = matching(rrs, question);
+          for r in answer.answers.iter_mut() {
+            r.set_domain(&question.name);
+          }
+        }
+        None => answer.flags.rcode = ResultCode::NXDOMAIN,
+      },
+    }
+    if answer.answers.is_empty() {
+      answer.authorities.push(self.negative_soa());
+    }
+    Some(answer)
+  }
+
+  /// The wildcard that stands in for `name`, which doesn't exist (RFC 4592 3.3.1).
+  ///
+  /// Only the wildcard directly below the closest encloser, the nearest ancestor
+  /// of `name` that does exist, can match. So with `*.dev.internal` and
+  /// `app.dev.internal` in the zone, `x.dev.internal` matches the wildcard but
+  /// `x.app.dev.internal` doesn't: its closest encloser is `app.dev.internal`,
+  /// which has no wildcard of its own.
+  fn wildcard(&self, name: &str) -> Option<&Vec<DnsRecord>> {
+    let mut encloser = name;
+    while encloser != self.origin {
+      encloser = encloser.split_once('.').map(|(_, p)| p).unwrap_or_default();
+      if self.names.contains(encloser) {
+        let source = if encloser.is_empty() {
+          "*".to_string()
+        } else {
+          format!("*.{}", encloser)
+        };
+        return self.records.get(&source);
+      }
+    }
+    None
+  }
+
+  /// The SOA as it goes out with a negative answer: its ttl is the lesser of its
+  /// own and its minimum field (RFC 2308 section 3)
+  fn negative_soa(&self) -> DnsRecord {
+    let mut soa = self.soa.clone();
+    if let DnsRecord::SOA { ttl, minimum, .. } = &mut soa {
+      *ttl = (*ttl).min(*minimum);
+    }
+    soa
+  }
+}
+
+/// The records at a name that answer `question`. A CNAME stands in for every
+/// other type at its name.
+fn matching(rrs: &[DnsRecord], question: &DnsQuestion) -> Vec<DnsRecord> {
+  rrs
+    .iter()
+    .filter(|r| {
+      question.qtype == QueryType::ANY
+        || r.qtype() == question.qtype
+        || r.qtype() == QueryType::CNAME
+    })
+    .cloned()
+    .collect()
+}
+
+/// What a master file's directives and earlier lines leave behind for later ones
+#[derive(Debug, Clone, Default)]
+struct Context {
+  // what relative names are relative to; $ORIGIN changes it
+  origin: String,
+  // from $TTL (RFC 2308 section 4)
+  default_ttl: Option<u32>,
+  // lines that start with whitespace belong to the last owner named
+  last_owner: Option<String>,
+  // without $TTL, a record with no ttl gets the last one given (RFC 1035 5.1)
+  last_ttl: Option<u32>,
+}
+
+#[derive(Debug, Default)]
+struct Parser {
+  records: Vec<DnsRecord>,
+}
+
+impl Parser {
+  fn parse_file(&mut self, path: &Path, mut cx: Context, depth: usize) -> Result<(), DnsError> {
+    if depth > MAX_INCLUDE_DEPTH {
+      return Err(
+        format!("{}: $INCLUDEs nested too deep", path.display())
+          .as_str()
+          .into(),
+      );
+    }
+    let text = fs::read_to_string(path)
+      .map_err(|e| DnsError::from(format!("{}: {}", path.display(), e).as_str()))?;
+    let entries =
+      tokenize(&text).map_err(|e| DnsError::from(format!("{}: {}", path.display(), e).as_str()))?;
+
+    for entry in entries {
+      let at =
+        |e: String| DnsError::from(format!("{}:{}: {}", path.display(), entry.line, e).as_str());
+      let first = &entry.tokens[0];
+      if !entry.blank_owner && first.starts_with('$') {
+        let arg = |i: usize| {
+          entry
+            .tokens
+            .get(i)
+            .map(|t| t.as_str())
+            .ok_or_else(|| at(format!("{} needs an argument", first)))
+        };
+        match first.to_uppercase().as_str() {
+          "$ORIGIN" => cx.origin = absolute(arg(1)?, &cx.origin),
+          "$TTL" => {
+            let ttl = arg(1)?;
+            cx.default_ttl = Some(parse_ttl(ttl).ok_or_else(|| at(format!("bad $TTL {:?}", ttl)))?);
+          }
+          "$INCLUDE" => {
+            // relative to the file doing the including
+            let included = path
+              .parent()
+              .unwrap_or_else(|| Path::new("."))
+              .join(arg(1)?);
+            let mut inner = cx.clone();
+            if let Ok(origin) = arg(2) {
+              inner.origin = absolute(origin, &cx.origin);
+            }
+            // whatever the included file does to its context stays in there
+            self.parse_file(&included, inner, depth + 1)?;
+          }
+          _ => return Err(at(format!("unsupported directive {}", first))),
+        }
+        continue;
+      }
+      let record = parse_record(&entry, &mut cx).map_err(at)?;
+      self.records.push(record);
+    }
+    Ok(())
+  }
+}
+
+/// One logical line of a master file: comments stripped, and anything inside
+/// parentheses joined up onto the line it started on
+#[derive(Debug)]
+struct Entry {
+  line: usize,
+  // the line started with whitespace, so it's for the previous owner
+  blank_owner: bool,
+  // with any backslash escapes left in, to be dealt with by whoever knows what
+  // each token is meant to be
+  tokens: Vec<String>,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Entry>, String> {
+  let mut entries = Vec::new();
+  let mut tokens: Vec<String> = Vec::new();
+  let mut current: Option<String> = None;
+  let mut depth = 0;
+  let mut in_quotes = false;
+  let mut line = 1;
+  let mut entry_line = 1;
+  let mut blank_owner = false;
+  let mut at_line_start = true;
+
+  let mut chars = text.chars().peekable();
+  while let Some(c) = chars.next() {
+    if at_line_start && depth == 0 && tokens.is_empty() && current.is_none() {
+      entry_line = line;
+      blank_owner = c == ' ' || c == '\t';
+    }
+    at_line_start = false;
+
+    if in_quotes {
+      let token = current.get_or_insert_with(String::new);
+      match c {
+        '"' => {
+          in_quotes = false;
+          tokens.extend(current.take());
+        }
+        '\\' => {
+          token.push(c);
+          if let Some(n) = chars.next() {
+            token.push(n);
+          }
+        }
+        '\n' => return Err(format!("line {}: unterminated quoted string", line)),
+        _ => token.push(c),
+      }
+      continue;
+    }
+
+    match c {
+      ';' => {
+        tokens.extend(current.take());
+        while chars.peek().map(|&n| n != '\n').unwrap_or(false) {
+          chars.next();
+        }
+      }
+      '"' => {
+        tokens.extend(current.take());
+        in_quotes = true;
+        current = Some(String::new());
+      }
+      '(' => {
+        tokens.extend(current.take());
+        depth += 1;
+      }
+      ')' => {
+        tokens.extend(current.take());
+        if depth == 0 {
+          return Err(format!("line {}: ) without a (", line));
+        }
+        depth -= 1;
+      }
+      '\\' => {
+        let token = current.get_or_insert_with(String::new);
+        token.push(c);
+        if let Some(n) = chars.next() {
+          token.push(n);
+        }
+      }
+      '\n' => {
+        tokens.extend(current.take());
+        line += 1;
+        at_line_start = true;
+        if depth == 0 && !tokens.is_empty() {
+          entries.push(Entry {
+            line: entry_line,
+            blank_owner,
+            tokens: mem::take(&mut tokens),
+          });
+        }
+      }
+      c if c.is_whitespace() => tokens.extend(current.take()),
+      c => current.get_or_insert_with(String::new).push(c),
+    }
+  }
+
+  if in_quotes {
+    return Err(format!("line {}: unterminated quoted string", line));
+  }
+  if depth > 0 {
+    return Err(format!("line {}: ( without a )", entry_line));
+  }
+  tokens.extend(current.take());
+  if !tokens.is_empty() {
+    entries.push(Entry {
+      line: entry_line,
+      blank_owner,
+      tokens,
+    });
+  }
+  Ok(entries)
+}
+
+/// `<owner> <ttl> <class> <type> <rdata...>`, where the owner can be left blank,
+/// and the ttl and class can be left out or come in either order.
+fn parse_record(entry: &Entry, cx: &mut Context) -> Result<DnsRecord, String> {
+  let mut tokens = entry.tokens.iter().map(|t| t.as_str()).peekable();
+  let domain = if entry.blank_owner {
+    cx.last_owner
+      .clone()
+      .ok_or("no owner for this record, and none before it")?
+  } else {
+    absolute(tokens.next().unwrap_or_default(), &cx.origin)
+  };
+
+  let mut ttl = None;
+  while let Some(&t) = tokens.peek() {
+    if t.eq_ignore_ascii_case("IN") {
+      tokens.next();
+    } else if ["CH", "HS", "CS"].iter().any(|c| t.eq_ignore_ascii_case(c)) {
+      return Err(format!("only class IN is supported, not {}", t));
+    } else if let (None, Some(parsed)) = (ttl, parse_ttl(t)) {
+      ttl = Some(parsed);
+      tokens.next();
+    } else {
+      break;
+    }
+  }
+  let ttl = ttl
+    .or(cx.default_ttl)
+    .or(cx.last_ttl)
+    .ok_or("no ttl for this record, and no $TTL to fall back on")?;
+  let type_name = tokens.next().ok_or("no record type")?;
+  let qtype = parse_type(type_name).ok_or_else(|| format!("unknown record type {}", type_name))?;
+
+  let skip = entry.tokens.len() - tokens.count();
+  let rdata = &entry.tokens[skip..];
+  let field = |i: usize| -> Result<&str, String> {
+    rdata
+      .get(i)
+      .map(|t| t.as_str())
+      .ok_or_else(|| format!("{} record is missing fields", type_name))
+  };
+  let number = |i: usize| -> Result<u32, String> {
+    let f = field(i)?;
+    f.parse::<u32>().map_err(|_| format!("bad number {:?}", f))
+  };
+  let duration = |i: usize| -> Result<u32, String> {
+    let f = field(i)?;
+    parse_ttl(f).ok_or_else(|| format!("bad time {:?}", f))
+  };
+  let name = |i: usize| -> Result<String, String> { Ok(absolute(field(i)?, &cx.origin)) };
+  let small = |i: usize| -> Result<u16, String> {
+    u16::try_from(number(i)?).map_err(|_| format!("{:?} is too big", field(i).unwrap_or_default()))
+  };
+
+  let fixed = match qtype {
+    QueryType::A | QueryType::NS | QueryType::CNAME | QueryType::PTR | QueryType::AAAA => Some(1),
+    QueryType::MX => Some(2),
+    QueryType::CAA => Some(3),
+    QueryType::SRV => Some(4),
+    QueryType::SOA => Some(7),
+    _ => None,
+  };
+  if rdata.first().map(|t| t == "\\#").unwrap_or(false) {
+    // RFC 3597 generic rdata, for types we don't know how to spell out
+    if fixed.is_some() || qtype == QueryType::TXT {
+      return Err(format!(
+        "write {} records out in full, not as \\#",
+        type_name
+      ));
+    }
+  } else if let Some(n) = fixed {
+    if rdata.len() != n {
+      return Err(format!(
+        "{} records take {} fields, not {}",
+        type_name,
+        n,
+        rdata.len()
+      ));
+    }
+  }
+
+  let record = match qtype {
+    QueryType::A => DnsRecord::A {
+      domain,
+      addr: field(0)?
+        .parse::<Ipv4Addr>()
+        .map_err(|_| format!("bad ipv4 address {:?}", field(0).unwrap_or_default()))?,
+      ttl,
+    },
+    QueryType::AAAA => DnsRecord::AAAA {
+      domain,
+      addr: field(0)?
+        .parse::<Ipv6Addr>()
+        .map_err(|_| format!("bad ipv6 address {:?}", field(0).unwrap_or_default()))?,
+      ttl,
+    },
+    QueryType::NS => DnsRecord::NS {
+      domain,
+      host: name(0)?,
+      ttl,
+    },
+    QueryType::CNAME => DnsRecord::CNAME {
+      domain,
+      host: name(0)?,
+      ttl,
+    },
+    QueryType::PTR => DnsRecord::PTR {
+      domain,
+      host: name(0)?,
+      ttl,
+    },
+    QueryType::MX => DnsRecord::MX {
+      domain,
+      priority: small(0)?,
+      host: name(1)?,
+      ttl,
+    },
+    QueryType::SOA => DnsRecord::SOA {
+      domain,
+      m_name: name(0)?,
+      r_name: name(1)?,
+      serial: number(2)?,
+      refresh: duration(3)?,
+      retry: duration(4)?,
+      expire: duration(5)?,
+      minimum: duration(6)?,
+      ttl,
+    },
+    QueryType::TXT => {
+      if rdata.is_empty() {
+        return Err("TXT records need at least one string".to_string());
+      }
+      DnsRecord::TXT {
+        domain,
+        data: rdata
+          .iter()
+          .map(|t| character_string(t))
+          .collect::<Result<Vec<Vec<u8>>, String>>()?,
+        ttl,
+      }
+    }
+    QueryType::SRV => DnsRecord::SRV {
+      domain,
+      priority: small(0)?,
+      weight: small(1)?,
+      port: small(2)?,
+      host: name(3)?,
+      ttl,
+    },
+    QueryType::CAA => DnsRecord::CAA {
+      domain,
+      flags: u8::try_from(number(0)?).map_err(|_| "CAA flags are a single byte".to_string())?,
+      tag: match field(1)? {
+        // the tag's length goes out as a single byte, and can't be zero (RFC 8659 4.1)
+        tag if tag.is_empty() || tag.len() > 0xFF => {
+          return Err(format!("CAA tag {:?} must be 1 to 255 bytes", tag))
+        }
+        tag => tag.to_string(),
+      },
+      value: unescape(field(2)?),
+      ttl,
+    },
+    _ => DnsRecord::UNKNOWN {
+      domain,
+      qtype: qtype.to_num(),
+      class: 1,
+      data: generic_rdata(rdata)?,
+      ttl,
+    },
+  };
+  cx.last_owner = Some(record.domain().to_string());
+  cx.last_ttl = Some(ttl);
+  Ok(record)
+}
+
+/// Make a name from a master file absolute: `@` is the origin itself, names ending
+/// in a dot are already absolute, and anything else is relative to the origin.
+fn absolute(name: &str, origin: &str) -> String {
+  if name == "@" {
+    origin.to_string()
+  } else if name.ends_with('.') || origin.is_empty() {
+    normalize_name(name)
+  } else {
+    normalize_name(&format!("{}.{}", name, origin))
+  }
+}
+
+/// A ttl in seconds, either as a plain number or in the `1h30m` style most
+/// servers accept (s, m, h, d and w)
+fn parse_ttl(s: &str) -> Option<u32> {
+  if let Ok(n) = s.parse::<u32>() {
+    return Some(n);
+  }
+  if !s.starts_with(|c: char| c.is_ascii_digit()) {
+    return None;
+  }
+  let mut total: u32 = 0;
+  let mut n: u32 = 0;
+  let mut digits = false;
+  for c in s.chars() {
+    if let Some(d) = c.to_digit(10) {
+      n = n.checked_mul(10)?.checked_add(d)?;
+      digits = true;
+      continue;
+    }
+    let unit = match c.to_ascii_lowercase() {
+      's' => 1,
+      'm' => 60,
+      'h' => 3600,
+      'd' => 86400,
+      'w' => 604800,
+      _ => return None,
+    };
+    if !digits {
+      return None;
+    }
+    total = total.checked_add(n.checked_mul(unit)?)?;
+    n = 0;
+    digits = false;
+  }
+  if digits {
+    return None;
+  }
+  Some(total)
+}
+
+/// A record type by its mnemonic, or as `TYPE65` for ones without one (RFC 3597)
+fn parse_type(s: &str) -> Option<QueryType> {
+  let s = s.to_uppercase();
+  let qtype = match s.as_str() {
+    "A" => QueryType::A,
+    "NS" => QueryType::NS,
+    "CNAME" => QueryType::CNAME,
+    "SOA" => QueryType::SOA,
+    "PTR" => QueryType::PTR,
+    "MX" => QueryType::MX,
+    "TXT" => QueryType::TXT,
+    "AAAA" => QueryType::AAAA,
+    "SRV" => QueryType::SRV,
+    "CAA" => QueryType::CAA,
+    _ => QueryType::from_num(s.strip_prefix("TYPE")?.parse::<u16>().ok()?),
+  };
+  // OPT only means anything inside a message, and ANY isn't something you can own
+  match qtype {
+    QueryType::OPT | QueryType::ANY | QueryType::UNKNOWN(0) => None,
+    _ => Some(qtype),
+  }
+}
+
+/// Turn `\X` into X and `\DDD` into the byte with that decimal value
+fn unescape(s: &str) -> Vec<u8> {
+  let bytes = s.as_bytes();
+  let mut out = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    if bytes[i] == b'\\' && i + 1 < bytes.len() {
+      let digits = &bytes[i + 1..(i + 4).min(bytes.len())];
+      if digits.len() == 3 && digits.iter().all(|b| b.is_ascii_digit()) {
+        let value = digits.iter().fold(0u16, |v, d| v * 10 + (d - b'0') as u16);
+        if value <= 255 {
+          out.push(value as u8);
+          i += 4;
+          continue;
+        }
+      }
+      out.push(bytes[i + 1]);
+      i += 2;
+    } else {
+      out.push(bytes[i]);
+      i += 1;
+    }
+  }
+  out
+}
+
+fn character_string(s: &str) -> Result<Vec<u8>, String> {
+  let bytes = unescape(s);
+  if bytes.len() > 255 {
+    return Err("character strings can't be longer than 255 bytes".to_string());
+  }
+  Ok(bytes)
+}
+
+/// `\# <length> <hex...>`, with the hex allowed to be split up with spaces
+fn generic_rdata(rdata: &[String]) -> Result<Vec<u8>, String> {
+  if rdata.first().map(|t| t.as_str()) != Some("\\#") {
+    return Err("records of unknown types need their rdata as \\# <length> <hex>".to_string());
+  }
+  let len = rdata
+    .get(1)
+    .and_then(|t| t.parse::<usize>().ok())
+    .ok_or("\\# needs a length")?;
+  let hex: String = rdata[2..].concat();
+  if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+    return Err(format!("bad hex {:?}", hex));
+  }
+  let data = (0..hex.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("bad hex {:?}", hex)))
+    .collect::<Result<Vec<u8>, String>>()?;
+  if data.len() != len {
+    return Err(format!(
+      "\\# says {} bytes but there are {}",
+      len,
+      data.len()
+    ));
+  }
+  Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{Fixture, SOA};
+
+  fn load(fixture: &Fixture, origin: &str, file: &str) -> Result<Zone, DnsError> {
+    Zone::load(&fixture.zone_file(origin, file))
+  }
+
+  #[test]
+  fn origin_and_ttl_directives() {
+    let text = format!(
+      "$TTL 1h\n{}www A 10.0.0.1\nmail 5m IN A 10.0.0.2\n\tA 10.0.0.3\n$ORIGIN lab.example.test.\nbox A 10.0.1.1\nabs.example.test. A 10.0.0.9\n",
+      SOA
+    );
+    let fixture = Fixture::new("directives", &[("db", &text)]);
+    let zone = load(&fixture, "example.test", "db").unwrap();
+
+    let www = zone
+      .lookup(&DnsQuestion::new("www.example.test", QueryType::A))
+      .unwrap();
+    assert!(www.flags.authoritative);
+    assert_eq!(
+      www.answers,
+      vec![DnsRecord::A {
+        domain: "www.example.test".into(),
+        addr: Ipv4Addr::new(10, 0, 0, 1),
+        ttl: 3600,
+      }]
+    );
+    // the blank owner carries on from the line before, but with $TTL in force
+    // a record without a ttl gets that rather than the last one given
+    let mail = zone
+      .lookup(&DnsQuestion::new("mail.example.test", QueryType::A))
+      .unwrap()
+      .answers;
+    assert_eq!(
+      mail.iter().map(|r| r.ttl()).collect::<Vec<_>>(),
+      vec![300, 3600]
+    );
+    for name in ["box.lab.example.test", "abs.example.test"] {
+      let answer = zone.lookup(&DnsQuestion::new(name, QueryType::A)).unwrap();
+      assert_eq!(answer.answers.len(), 1, "{}", name);
+    }
+  }
+
+  #[test]
+  fn names_outside_the_zone_arent_ours() {
+    let text = format!("$TTL 60\n{}www A 10.0.0.1\n", SOA);
+    let fixture = Fixture::new("outside", &[("db", &text)]);
+    let zone = load(&fixture, "example.test", "db").unwrap();
+    assert!(zone
+      .lookup(&DnsQuestion::new("www.other.test", QueryType::A))
+      .is_none());
+
+    // a type we don't have is NODATA, a name we don't have NXDOMAIN
+    let nodata = zone
+      .lookup(&DnsQuestion::new("www.example.test", QueryType::AAAA))
+      .unwrap();
+    assert_eq!(nodata.flags.rcode, ResultCode::NOERROR);
+    assert!(nodata.answers.is_empty());
+    assert_eq!(nodata.authorities.len(), 1);
+    let nx = zone
+      .lookup(&DnsQuestion::new("nope.example.test", QueryType::A))
+      .unwrap();
+    assert_eq!(nx.flags.rcode, ResultCode::NXDOMAIN);
+  }
+
+  #[test]
+  fn parenthesized_soa() {
+    let text = "$TTL 300\n@ IN SOA ns1.example.test. hostmaster.example.test. (\n  2024010101 ; serial\n  2h         ; refresh\n  1h         ; retry\n  2w         ; expire\n  60 )       ; minimum\n";
+    let fixture = Fixture::new("soa", &[("db", text)]);
+    let zone = load(&fixture, "example.test", "db").unwrap();
+    assert_eq!(
+      zone.soa,
+      DnsRecord::SOA {
+        domain: "example.test".into(),
+        m_name: "ns1.example.test".into(),
+        r_name: "hostmaster.example.test".into(),
+        serial: 2024010101,
+        refresh: 7200,
+        retry: 3600,
+        expire: 1209600,
+        minimum: 60,
+        ttl: 300,
+      }
+    );
+    // negative answers carry the SOA with the lesser of its ttl and minimum
+    let nx = zone
+      .lookup(&DnsQuestion::new("nope.example.test", QueryType::A))
+      .unwrap();
+    assert_eq!(nx.flags.rcode, ResultCode::NXDOMAIN);
+    assert_eq!(nx.authorities[0].ttl(), 60);
+  }
+
+  #[test]
+  fn include_with_its_own_origin() {
+    let main = format!(
+      "$TTL 60\n{}$INCLUDE hosts.inc lab.example.test.\nafter A 10.0.0.2\n",
+      SOA
+    );
+    let fixture = Fixture::new(
+      "include",
+      &[("db", &main), ("hosts.inc", "$TTL 30\nbox A 10.0.1.1\n")],
+    );
+    let zone = load(&fixture, "example.test", "db").unwrap();
+    let ttl = |name: &str| {
+      zone
+        .lookup(&DnsQuestion::new(name, QueryType::A))
+        .unwrap()
+        .answers[0]
+        .ttl()
+    };
+    assert_eq!(ttl("box.lab.example.test"), 30);
+    // neither the include's origin nor its $TTL leak back out
+    assert_eq!(ttl("after.example.test"), 60);
+  }
+
+  #[test]
+  fn include_loops_are_caught() {
+    let fixture = Fixture::new("include-loop", &[("db", "$TTL 60\n$INCLUDE db\n")]);
+    assert!(load(&fixture, "example.test", "db").is_err());
+  }
+
+  #[test]
+  fn wildcards_match_below_the_closest_encloser() {
+    let text = format!(
+      "$TTL 60\n{}*.dev A 10.5.0.1\napp.dev A 10.5.0.2\na.b.deep A 10.5.0.3\n",
+      SOA
+    );
+    let fixture = Fixture::new("wildcard", &[("db", &text)]);
+    let zone = load(&fixture, "example.test", "db").unwrap();
+    let ask = |name: &str, qtype: QueryType| zone.lookup(&DnsQuestion::new(name, qtype)).unwrap();
+
+    let hit = ask("x.dev.example.test", QueryType::A);
+    assert_eq!(hit.flags.rcode, ResultCode::NOERROR);
+    assert!(hit.flags.authoritative);
+    assert_eq!(hit.answers[0].domain(), "x.dev.example.test");
+
+    // app.dev exists, so it's the closest encloser and has no wildcard of its own
+    let miss = ask("x.app.dev.example.test", QueryType::A);
+    assert_eq!(miss.flags.rcode, ResultCode::NXDOMAIN);
+
+    // the wildcard has nothing of this type: NODATA, not NXDOMAIN
+    let nodata = ask("y.dev.example.test", QueryType::AAAA);
+    assert_eq!(nodata.flags.rcode, ResultCode::NOERROR);
+    assert!(nodata.answers.is_empty());
+    assert_eq!(nodata.authorities.len(), 1);
+
+    // b.deep owns nothing but has a name under it, so it exists
+    let empty = ask("b.deep.example.test", QueryType::A);
+    assert_eq!(empty.flags.rcode, ResultCode::NOERROR);
+    assert!(empty.answers.is_empty());
+  }
+
+  #[test]
+  fn bad_zones_are_rejected() {
+    let two_soas = format!("$TTL 60\n{}{}", SOA, SOA);
+    let long_tag = format!("$TTL 60\n{}@ CAA 0 {} \"v\"\n", SOA, "a".repeat(256));
+    let empty_tag = format!("$TTL 60\n{}@ CAA 0 \"\" \"v\"\n", SOA);
+    let fixture = Fixture::new(
+      "bad",
+      &[
+        ("no-soa", "$TTL 60\nwww A 10.0.0.1\n"),
+        ("two-soas", &two_soas),
+        ("long-tag", &long_tag),
+        ("empty-tag", &empty_tag),
+        ("unbalanced", "$TTL 60\n@ SOA ns hm ( 1 2 3 4 5\n"),
+      ],
+    );
+    for file in ["no-soa", "two-soas", "long-tag", "empty-tag", "unbalanced"] {
+      assert!(load(&fixture, "example.test", file).is_err(), "{}", file);
+    }
+  }
+
+  #[test]
+  fn ttl_units() {
+    assert_eq!(parse_ttl("300"), Some(300));
+    assert_eq!(parse_ttl("1h30m"), Some(5400));
+    assert_eq!(parse_ttl("1W"), Some(604800));
+    assert_eq!(parse_ttl("h"), None);
+    assert_eq!(parse_ttl("10"), Some(10));
+    assert_eq!(parse_ttl("5x"), None);
+    assert_eq!(parse_ttl("1h5"), None);
+  }
+
+  #[test]
+  fn tokenizer_handles_quotes_and_escapes() {
+    let entries = tokenize("@ TXT \"a ; not a comment\" b\\ c ; comment\n").unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(
+      entries[0].tokens,
+      vec!["@", "TXT", "a ; not a comment", "b\\ c"]
+    );
+    assert!(tokenize("@ TXT \"open\n").is_err());
+    assert!(tokenize("@ A 10.0.0.1 )\n").is_err());
+  }
+}